@@ -0,0 +1,16 @@
+DROP TABLE deck_subscriptions;
+DROP TABLE course_subscriptions;
+DROP TABLE followers;
+DROP TABLE coursedecks;
+DROP TABLE addons;
+DROP TABLE notifications;
+DROP TABLE settings;
+DROP TABLE study_sessions;
+DROP TABLE reviews;
+DROP TABLE history;
+DROP TABLE media;
+DROP TABLE cards;
+DROP TABLE decks;
+DROP TABLE courses;
+DROP TABLE credentials;
+DROP TABLE users;
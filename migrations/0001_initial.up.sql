@@ -0,0 +1,152 @@
+-- Generated from the #[model] definitions in src/models.rs
+
+CREATE TABLE users (
+    id SERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    email TEXT NOT NULL,
+    real_name TEXT,
+    visibility BOOLEAN,
+    verified BOOLEAN,
+    created_at TIMESTAMPTZ NOT NULL,
+    last_login TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE credentials (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    password TEXT NOT NULL
+);
+
+CREATE TABLE courses (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    visibility BOOLEAN,
+    name TEXT NOT NULL,
+    image BYTEA NOT NULL
+);
+
+CREATE TABLE decks (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    visibility BOOLEAN,
+    name TEXT NOT NULL,
+    image BYTEA NOT NULL
+);
+
+CREATE TABLE cards (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    deck_id INTEGER NOT NULL REFERENCES decks(id) ON DELETE CASCADE,
+    front BYTEA NOT NULL,
+    back BYTEA NOT NULL,
+    tags TEXT[] NOT NULL,
+    version INTEGER NOT NULL DEFAULT 1
+);
+
+CREATE TABLE media (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    deck_id INTEGER NOT NULL REFERENCES decks(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    data BYTEA NOT NULL
+);
+
+CREATE TABLE history (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    card_id INTEGER NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+    ts TIMESTAMPTZ NOT NULL,
+    num_confident INTEGER NOT NULL,
+    num_correct INTEGER NOT NULL,
+    num_wrong INTEGER NOT NULL,
+    ease DOUBLE PRECISION NOT NULL,
+    interval INTEGER NOT NULL,
+    repetitions INTEGER NOT NULL,
+    lapses INTEGER NOT NULL,
+    stability DOUBLE PRECISION NOT NULL,
+    difficulty DOUBLE PRECISION NOT NULL,
+    due TIMESTAMPTZ NOT NULL,
+    time_spent BIGINT NOT NULL
+);
+
+CREATE TABLE reviews (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    card_id INTEGER NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+    ts TIMESTAMPTZ NOT NULL,
+    grade INTEGER NOT NULL,
+    response_time INTEGER NOT NULL,
+    undone BOOLEAN NOT NULL,
+    session_id INTEGER,
+    prev_ts TIMESTAMPTZ,
+    prev_ease DOUBLE PRECISION,
+    prev_interval INTEGER,
+    prev_repetitions INTEGER,
+    prev_lapses INTEGER,
+    prev_stability DOUBLE PRECISION,
+    prev_difficulty DOUBLE PRECISION,
+    prev_due TIMESTAMPTZ
+);
+
+CREATE TABLE study_sessions (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    deck_id INTEGER,
+    course_id INTEGER,
+    started_at TIMESTAMPTZ NOT NULL,
+    finished_at TIMESTAMPTZ,
+    cards_seen INTEGER NOT NULL,
+    num_reviews INTEGER NOT NULL,
+    num_correct INTEGER NOT NULL,
+    time_spent BIGINT NOT NULL
+);
+
+CREATE TABLE settings (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    avatar BYTEA NOT NULL,
+    algorithm TEXT,
+    new_cards_per_day INTEGER,
+    reviews_per_day INTEGER
+);
+
+CREATE TABLE notifications (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    ts TIMESTAMPTZ NOT NULL,
+    message TEXT NOT NULL,
+    icon BYTEA NOT NULL
+);
+
+CREATE TABLE addons (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    visibility BOOLEAN,
+    name TEXT NOT NULL,
+    description TEXT NOT NULL,
+    data BYTEA NOT NULL
+);
+
+CREATE TABLE coursedecks (
+    id SERIAL PRIMARY KEY,
+    course_id INTEGER NOT NULL REFERENCES courses(id) ON DELETE CASCADE,
+    deck_id INTEGER NOT NULL REFERENCES decks(id) ON DELETE CASCADE
+);
+
+CREATE TABLE followers (
+    id SERIAL PRIMARY KEY,
+    follower_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    following_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE course_subscriptions (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    course_id INTEGER NOT NULL REFERENCES courses(id) ON DELETE CASCADE
+);
+
+CREATE TABLE deck_subscriptions (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    deck_id INTEGER NOT NULL REFERENCES decks(id) ON DELETE CASCADE
+);
@@ -0,0 +1,13 @@
+DROP INDEX deck_subscriptions_user_deck;
+DROP INDEX course_subscriptions_user_course;
+DROP INDEX coursedecks_course_deck;
+DROP INDEX reviews_session;
+DROP INDEX reviews_user_ts;
+DROP INDEX media_deck;
+DROP INDEX cards_deck;
+DROP INDEX history_user_due;
+DROP INDEX history_user_card;
+DROP INDEX settings_user;
+DROP INDEX credentials_user;
+DROP INDEX users_email;
+DROP INDEX users_username;
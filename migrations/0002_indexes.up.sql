@@ -0,0 +1,14 @@
+CREATE UNIQUE INDEX users_username ON users (username);
+CREATE UNIQUE INDEX users_email ON users (email);
+CREATE UNIQUE INDEX credentials_user ON credentials (user_id);
+CREATE UNIQUE INDEX settings_user ON settings (user_id);
+-- One scheduling state per user and card
+CREATE UNIQUE INDEX history_user_card ON history (user_id, card_id);
+CREATE INDEX history_user_due ON history (user_id, due);
+CREATE INDEX cards_deck ON cards (deck_id);
+CREATE INDEX media_deck ON media (deck_id);
+CREATE INDEX reviews_user_ts ON reviews (user_id, ts);
+CREATE INDEX reviews_session ON reviews (session_id);
+CREATE UNIQUE INDEX coursedecks_course_deck ON coursedecks (course_id, deck_id);
+CREATE UNIQUE INDEX course_subscriptions_user_course ON course_subscriptions (user_id, course_id);
+CREATE UNIQUE INDEX deck_subscriptions_user_deck ON deck_subscriptions (user_id, deck_id);
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE users ADD COLUMN admin BOOLEAN NOT NULL DEFAULT false;
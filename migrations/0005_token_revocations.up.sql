@@ -0,0 +1,9 @@
+CREATE TABLE token_revocations (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    jti TEXT,
+    revoked_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX token_revocations_user ON token_revocations (user_id);
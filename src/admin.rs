@@ -0,0 +1,417 @@
+//! Command line tool for operating a Memra database: migrations, user management and demo data.
+//! Connects to `DATABASE_URL`, or to the `main` database configured for Rocket.
+
+#[macro_use]
+extern crate rocket;
+
+#[path = "db.rs"]
+mod db;
+#[allow(dead_code)]
+#[path = "models.rs"]
+mod models;
+#[allow(dead_code)]
+#[path = "auth.rs"]
+mod auth;
+#[allow(dead_code)]
+#[path = "user.rs"]
+mod user;
+#[allow(dead_code)]
+#[path = "session.rs"]
+mod session;
+#[allow(dead_code)]
+#[path = "mail.rs"]
+mod mail;
+#[allow(dead_code)]
+#[path = "verification.rs"]
+mod verification;
+#[allow(dead_code)]
+#[path = "two_factor.rs"]
+mod two_factor;
+#[allow(dead_code)]
+#[path = "migrations.rs"]
+mod migrations;
+
+use clap::{Parser, Subcommand};
+use rand_core::{OsRng, RngCore};
+use rocket_db_pools::sqlx::{self, Acquire, PgConnection, PgPool, Row};
+use db::{Db, Result};
+use models::*;
+use auth::Role;
+
+#[derive(Parser)]
+#[clap(name = "memra-admin", about = "Administers a Memra database")]
+struct Cli {
+    /// Postgres connection string, instead of DATABASE_URL or the Rocket configuration
+    #[clap(long)]
+    database_url: Option<String>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Applies all pending migrations
+    Migrate,
+    /// Reverts the most recent migrations
+    Rollback {
+        #[clap(long, default_value_t = 1)]
+        steps: usize,
+    },
+    /// Prints the CREATE TABLE statements generated from the models
+    Schema,
+    /// Manages user accounts
+    #[clap(subcommand)]
+    User(UserCommand),
+    /// Creates a demo user with a few decks and cards, printing a generated password unless one is given
+    Seed {
+        #[clap(long, default_value = "demo")]
+        username: String,
+        #[clap(long)]
+        password: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Creates a verified user, printing a generated password unless one is given
+    Create {
+        username: String,
+        email: String,
+        #[clap(long)]
+        password: Option<String>,
+        #[clap(long)]
+        real_name: Option<String>,
+        /// user, moderator or admin
+        #[clap(long, default_value = "user")]
+        role: String,
+    },
+    /// Prevents a user from logging in and revokes their tokens
+    Disable { username: String },
+    /// Allows a disabled user to log in again
+    Enable { username: String },
+    /// Deletes a user together with everything they own, revoking their tokens
+    Delete { username: String },
+    /// Sets a new password and ends all sessions, printing a generated password unless one is given
+    ResetPassword {
+        username: String,
+        #[clap(long)]
+        password: Option<String>,
+    },
+    /// Makes a user an administrator
+    Promote { username: String },
+    /// Gives a user the role user, moderator or admin
+    SetRole { username: String, role: String },
+}
+
+/// CREATE TABLE statements of every model, in an order that satisfies their foreign keys
+fn schema() -> Vec<String> {
+    vec![
+        User::create_table_sql(),
+        Credentials::create_table_sql(),
+        Course::create_table_sql(),
+        Deck::create_table_sql(),
+        Card::create_table_sql(),
+        Media::create_table_sql(),
+        History::create_table_sql(),
+        Review::create_table_sql(),
+        StudySession::create_table_sql(),
+        Settings::create_table_sql(),
+        Notification::create_table_sql(),
+        Addon::create_table_sql(),
+        CourseDeck::create_table_sql(),
+        Followers::create_table_sql(),
+        CourseSubscription::create_table_sql(),
+        DeckSubscription::create_table_sql(),
+        Session::create_table_sql(),
+        Revocation::create_table_sql(),
+        PasswordReset::create_table_sql(),
+        TwoFactor::create_table_sql(),
+        RecoveryCode::create_table_sql(),
+        Identity::create_table_sql(),
+        ApiKey::create_table_sql(),
+    ]
+}
+
+fn database_url(url: Option<String>) -> Result<String, String> {
+    if let Some(url) = url.or_else(|| std::env::var("DATABASE_URL").ok()) {
+        return Ok(url);
+    }
+    rocket::Config::figment()
+        .extract_inner("databases.main.url")
+        .map_err(|_| "No database configured. Set DATABASE_URL or pass --database-url.".to_string())
+}
+
+fn generate_password() -> String {
+    let mut bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash(password: &String) -> Result<String, String> {
+    user::hash(password).map_err(|e| e.1)
+}
+
+async fn find_user(conn: &mut PgConnection, username: &str) -> Result<User, String> {
+    sqlx::query(format!("SELECT * FROM {} WHERE username = $1", User::table()).as_str())
+        .bind(username)
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| e.to_string())?
+        .map(User::from)
+        .ok_or_else(|| format!("User {} does not exist.", username))
+}
+
+/// Sets a flag of a user, returning their id
+async fn set_flag(pool: &PgPool, username: &str, flag: &str, value: bool) -> Result<i32, String> {
+    sqlx::query(format!("UPDATE {} SET {} = $1 WHERE username = $2 RETURNING id", User::table(), flag).as_str())
+        .bind(value)
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| e.to_string())?
+        .map(|row| row.get(0))
+        .ok_or_else(|| format!("User {} does not exist.", username))
+}
+
+fn parse_role(name: &str) -> Result<Role, String> {
+    Role::parse(name).ok_or_else(|| format!("Unknown role {}. Use user, moderator or admin.", name))
+}
+
+/// Revokes all tokens of a user like the server does, for the token lifetime in the Rocket configuration
+async fn revoke_tokens(conn: &mut PgConnection, user_id: i32) -> Result<(), String> {
+    let lifetime = rocket::Config::figment()
+        .extract_inner("auth.token_lifetime")
+        .map(chrono::Duration::seconds)
+        .unwrap_or(*auth::TOKEN_EXPIRATION);
+    auth::revoke_user_tokens(conn, user_id, lifetime).await.map_err(|e| e.to_string())
+}
+
+/// Changes the role of a user, whose tokens are revoked so they can't keep using the previous role
+async fn set_role(pool: &PgPool, username: &str, role: Role) -> Result<(), String> {
+    let updated = sqlx::query(format!("UPDATE {} SET role = $1 WHERE username = $2 RETURNING id", User::table()).as_str())
+        .bind(role.as_str())
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    match updated {
+        None => Err(format!("User {} does not exist.", username)),
+        Some(row) => {
+            let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+            revoke_tokens(&mut conn, row.get(0)).await
+        }
+    }
+}
+
+async fn create_user(conn: &mut PgConnection, username: String, email: String, real_name: Option<String>, password: &String, role: Role) -> Result<User, String> {
+    let now = chrono::Utc::now();
+    let mut tx = conn.begin().await.map_err(|e| e.to_string())?;
+
+    let user = User::new(username, email, real_name, Some(true), Some(true), now, now, false, role.as_str().to_string())
+        .save_in(&mut *tx).await.map_err(|e| e.to_string())?;
+    Credentials::new_from(&user, hash(password)?).unwrap()
+        .save_in(&mut *tx).await.map_err(|e| e.to_string())?;
+
+    tx.commit().await.map_err(|e| e.to_string())?;
+    Ok(user)
+}
+
+async fn rollback(pool: &PgPool, steps: usize) -> Result<(), String> {
+    let applied = sqlx::query("SELECT version FROM _sqlx_migrations ORDER BY version DESC")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    // Everything newer than the target version is reverted
+    let target = applied.get(steps).map_or(0, |r| r.get::<i64, _>(0));
+
+    migrations::MIGRATOR.undo(pool, target).await.map_err(|e| e.to_string())
+}
+
+/// A deck name and its cards as front, back and tags
+type DemoDeck = (&'static str, &'static [(&'static str, &'static str, &'static str)]);
+
+const DEMO_DECKS: &[DemoDeck] = &[
+    ("German basics", &[
+        ("der Hund", "the dog", "noun animals"),
+        ("die Katze", "the cat", "noun animals"),
+        ("das Haus", "the house", "noun"),
+        ("laufen", "to run", "verb"),
+        ("schnell", "fast", "adjective"),
+    ]),
+    ("European capitals", &[
+        ("France", "Paris", "europe"),
+        ("Germany", "Berlin", "europe"),
+        ("Italy", "Rome", "europe"),
+        ("Spain", "Madrid", "europe"),
+        ("Poland", "Warsaw", "europe"),
+    ]),
+];
+
+async fn seed(pool: &PgPool, username: String, password: Option<String>) -> Result<(), String> {
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    let user = match find_user(&mut conn, &username).await {
+        Ok(user) => user,
+        Err(_) => {
+            let email = format!("{}@memra.app", username);
+            let generated = password.is_none();
+            let password = password.unwrap_or_else(generate_password);
+            let user = create_user(&mut conn, username.clone(), email, None, &password, Role::User).await?;
+            println!("Created user {}", username);
+            if generated {
+                println!("Password: {}", password);
+            }
+            user
+        }
+    };
+    let user_id = user.id.unwrap();
+
+    let mut tx = conn.begin().await.map_err(|e| e.to_string())?;
+    for (name, cards) in DEMO_DECKS {
+        let deck = Deck::new(user_id, None, name.to_string(), vec![])
+            .save_in(&mut *tx).await.map_err(|e| e.to_string())?;
+        for (front, back, tags) in cards.iter() {
+            let tags = tags.split_whitespace().map(|t| t.to_string()).collect();
+            Card::new(user_id, deck.id.unwrap(), front.as_bytes().to_vec(), back.as_bytes().to_vec(), tags)
+                .save_in(&mut *tx).await.map_err(|e| e.to_string())?;
+        }
+        println!("Created deck {} with {} cards", name, cards.len());
+    }
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+async fn run(cli: Cli) -> Result<(), String> {
+    if let Command::Schema = cli.command {
+        for table in schema() {
+            println!("{};\n", table);
+        }
+        return Ok(());
+    }
+
+    let pool = PgPool::connect(&database_url(cli.database_url)?).await.map_err(|e| e.to_string())?;
+
+    match cli.command {
+        Command::Schema => unreachable!(),
+        Command::Migrate => migrations::MIGRATOR.run(&pool).await.map_err(|e| e.to_string()),
+        Command::Rollback { steps } => rollback(&pool, steps).await,
+        Command::Seed { username, password } => seed(&pool, username, password).await,
+        Command::User(command) => match command {
+            UserCommand::Create { username, email, password, real_name, role } => {
+                let role = parse_role(&role)?;
+                let generated = password.is_none();
+                let password = password.unwrap_or_else(generate_password);
+                let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+                let user = create_user(&mut conn, username, email, real_name, &password, role).await?;
+                println!("Created user {} with id {}", user.username, user.id.unwrap());
+                if generated {
+                    println!("Password: {}", password);
+                }
+                Ok(())
+            }
+            UserCommand::Disable { username } => {
+                // Logging in and refreshing check the flag, but tokens issued before don't
+                let id = set_flag(&pool, &username, "disabled", true).await?;
+                let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+                revoke_tokens(&mut conn, id).await
+            }
+            UserCommand::Enable { username } => set_flag(&pool, &username, "disabled", false).await.map(|_| ()),
+            UserCommand::Promote { username } => set_role(&pool, &username, Role::Admin).await,
+            UserCommand::SetRole { username, role } => set_role(&pool, &username, parse_role(&role)?).await,
+            UserCommand::Delete { username } => {
+                // Owned rows are removed by ON DELETE CASCADE
+                let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+                let user = find_user(&mut conn, &username).await?;
+                let mut tx = conn.begin().await.map_err(|e| e.to_string())?;
+                // Revocations aren't tied to the user row, so they outlast it until the tokens expire
+                revoke_tokens(&mut *tx, user.id.unwrap()).await?;
+                sqlx::query(format!("DELETE FROM {} WHERE id = $1", User::table()).as_str())
+                    .bind(user.id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                tx.commit().await.map_err(|e| e.to_string())
+            }
+            UserCommand::ResetPassword { username, password } => {
+                let generated = password.is_none();
+                let password = password.unwrap_or_else(generate_password);
+                let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+                let user = find_user(&mut conn, &username).await?;
+                let mut tx = conn.begin().await.map_err(|e| e.to_string())?;
+                sqlx::query(format!("UPDATE {} SET password = $1 WHERE user_id = $2", Credentials::table()).as_str())
+                    .bind(hash(&password)?)
+                    .bind(user.id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                // Like a reset through the app, this logs the user out everywhere
+                revoke_tokens(&mut *tx, user.id.unwrap()).await?;
+                session::revoke_all(&mut *tx, user.id.unwrap()).await.map_err(|e| e.to_string())?;
+                tx.commit().await.map_err(|e| e.to_string())?;
+                if generated {
+                    println!("Password: {}", password);
+                }
+                Ok(())
+            }
+        },
+    }
+}
+
+#[rocket::main]
+async fn main() {
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    type Column = (String, String, String, String);
+
+    async fn columns(conn: &mut PgConnection, schema: &str) -> BTreeSet<Column> {
+        sqlx::query("SELECT table_name::text, column_name::text, data_type::text, is_nullable::text FROM information_schema.columns WHERE table_schema = $1")
+            .bind(schema)
+            .fetch_all(conn)
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| (r.get(0), r.get(1), r.get(2), r.get(3)))
+            .collect()
+    }
+
+    /// The migrations must create exactly the tables that `schema()` derives from the models
+    #[rocket::async_test]
+    async fn migrations_match_models() {
+        let url = match std::env::var("DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("DATABASE_URL is not set, skipping");
+                return;
+            }
+        };
+        let mut conn: PgConnection = sqlx::Connection::connect(&url).await.unwrap();
+        migrations::MIGRATOR.run(&mut conn).await.unwrap();
+
+        // Create the model tables in a scratch schema, which is dropped again with the rollback
+        let mut tx = conn.begin().await.unwrap();
+        sqlx::query("CREATE SCHEMA schema_check").execute(&mut *tx).await.unwrap();
+        sqlx::query("SET LOCAL search_path TO schema_check").execute(&mut *tx).await.unwrap();
+        for sql in schema() {
+            sqlx::query(&sql).execute(&mut *tx).await.unwrap();
+        }
+
+        let models = columns(&mut tx, "schema_check").await;
+        let migrated: BTreeSet<Column> = columns(&mut tx, "public").await
+            .into_iter()
+            .filter(|c| c.0 != "_sqlx_migrations")
+            .collect();
+        tx.rollback().await.unwrap();
+
+        let missing: Vec<_> = models.difference(&migrated).collect();
+        let extra: Vec<_> = migrated.difference(&models).collect();
+        assert!(missing.is_empty() && extra.is_empty(), "not created by the migrations: {:?}\nnot in the models: {:?}", missing, extra);
+    }
+}
@@ -0,0 +1,294 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rocket::serde::json::{self, Value, json};
+use rusqlite::{OpenFlags, params};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+use super::scheduler::SchedulingState;
+
+/// Anki separates the fields of a note with the unit separator character
+pub const FIELD_SEPARATOR: char = '\x1f';
+/// Largest collection database a package may contain
+const COLLECTION_LIMIT: u64 = 1024 * 1024 * 1024;
+/// Largest media file taken from a package; bigger ones are left out
+const MEDIA_FILE_LIMIT: u64 = 64 * 1024 * 1024;
+/// Largest total size of the media taken from a package
+const MEDIA_TOTAL_LIMIT: u64 = 512 * 1024 * 1024;
+
+/// Schema of an Anki 2.1 (schema version 11) collection
+const COLLECTION_SCHEMA: &str = "
+    CREATE TABLE col (id integer primary key, crt integer not null, mod integer not null, scm integer not null,
+        ver integer not null, dty integer not null, usn integer not null, ls integer not null, conf text not null,
+        models text not null, decks text not null, dconf text not null, tags text not null);
+    CREATE TABLE notes (id integer primary key, guid text not null, mid integer not null, mod integer not null,
+        usn integer not null, tags text not null, flds text not null, sfld text not null, csum integer not null,
+        flags integer not null, data text not null);
+    CREATE TABLE cards (id integer primary key, nid integer not null, did integer not null, ord integer not null,
+        mod integer not null, usn integer not null, type integer not null, queue integer not null, due integer not null,
+        ivl integer not null, factor integer not null, reps integer not null, lapses integer not null, left integer not null,
+        odue integer not null, odid integer not null, flags integer not null, data text not null);
+    CREATE TABLE revlog (id integer primary key, cid integer not null, usn integer not null, ease integer not null,
+        ivl integer not null, lastIvl integer not null, factor integer not null, time integer not null, type integer not null);
+    CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+";
+
+#[derive(Debug)]
+pub struct AnkiNote {
+    pub id: i64,
+    pub fields: Vec<String>,
+    pub tags: Vec<String>,
+    /// Scheduling state to carry over into the card of this note, if any
+    pub scheduling: Option<SchedulingState>,
+}
+
+/// One entry of the Anki review log, attributed to the note its card belongs to
+#[derive(Debug)]
+pub struct AnkiReview {
+    pub note_id: i64,
+    pub ts: DateTime<Utc>,
+    /// Answer button pressed, from 1 (again) to 4 (easy)
+    pub ease: i32,
+    /// Interval in days when positive, in seconds (while learning) when negative
+    pub interval: i32,
+    /// Interval before this answer, in the same units
+    pub last_interval: i32,
+    /// Ease factor in permille
+    pub factor: i32,
+    /// Time taken to answer in milliseconds
+    pub time: i32,
+}
+
+#[derive(Debug, Default)]
+pub struct AnkiPackage {
+    pub name: Option<String>,
+    pub notes: Vec<AnkiNote>,
+    pub media: Vec<(String, Vec<u8>)>,
+    pub reviews: Vec<AnkiReview>,
+}
+
+fn zip_error(e: zip::result::ZipError) -> String {
+    format!("Not a valid Anki package: {}", e)
+}
+
+fn sqlite_error(e: rusqlite::Error) -> String {
+    format!("Could not read Anki collection: {}", e)
+}
+
+fn io_error(e: io::Error) -> String {
+    format!("Could not read Anki package: {}", e)
+}
+
+fn write_error<E: std::fmt::Display>(e: E) -> String {
+    format!("Could not write Anki package: {}", e)
+}
+
+impl AnkiPackage {
+    /// Reads an `.apkg` file, which is a zip archive holding a SQLite collection,
+    /// a `media` index mapping numbered entries to file names, and those media files.
+    /// `scratch` is a directory the collection can be extracted to.
+    pub fn read(path: &Path, scratch: &Path) -> Result<Self, String> {
+        let mut archive = zip::ZipArchive::new(File::open(path).map_err(io_error)?).map_err(zip_error)?;
+
+        // Anki 2.1 packages also include an empty legacy collection for older clients
+        let collection = ["collection.anki21", "collection.anki2"]
+            .into_iter()
+            .find(|name| archive.by_name(name).is_ok());
+
+        if collection.is_none() {
+            return Err(if archive.by_name("collection.anki21b").is_ok() {
+                "This package uses the newest Anki format. Please export it with \"Support older Anki versions\" checked.".to_string()
+            } else {
+                "Not a valid Anki package: no collection found.".to_string()
+            });
+        }
+
+        // Like media below, the collection is only extracted up to a limit
+        let collection_path = scratch.join("collection.sqlite");
+        let copied = io::copy(
+            &mut archive.by_name(collection.unwrap()).map_err(zip_error)?.take(COLLECTION_LIMIT + 1),
+            &mut File::create(&collection_path).map_err(io_error)?,
+        ).map_err(io_error)?;
+        if copied > COLLECTION_LIMIT {
+            return Err(format!("The collection in this package is larger than {} MiB.", COLLECTION_LIMIT / 1024 / 1024));
+        }
+
+        let mut package = Self::read_collection(&collection_path)?;
+
+        let mut index = String::new();
+        if let Ok(media) = archive.by_name("media") {
+            media.take(MEDIA_FILE_LIMIT).read_to_string(&mut index).map_err(io_error)?;
+        }
+        // Packages without media (or with a binary index) simply bring no media along
+        let index: HashMap<String, String> = json::from_str(&index).unwrap_or_default();
+
+        // Sizes in the archive can't be trusted, so files are only read up to the limits
+        let mut total = 0;
+        for (entry, name) in index {
+            let file = match archive.by_name(&entry) {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Skipping media file {} missing from package: {}", name, e);
+                    continue;
+                }
+            };
+
+            let mut data = Vec::new();
+            file.take(MEDIA_FILE_LIMIT + 1).read_to_end(&mut data).map_err(io_error)?;
+            if data.len() as u64 > MEDIA_FILE_LIMIT {
+                warn!("Skipping media file {} larger than {} bytes", name, MEDIA_FILE_LIMIT);
+                continue;
+            }
+
+            total += data.len() as u64;
+            if total > MEDIA_TOTAL_LIMIT {
+                return Err(format!("The media in this package is larger than {} MiB.", MEDIA_TOTAL_LIMIT / 1024 / 1024));
+            }
+            package.media.push((name, data));
+        }
+
+        Ok(package)
+    }
+
+    fn read_collection(path: &Path) -> Result<Self, String> {
+        let db = rusqlite::Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(sqlite_error)?;
+
+        let decks: String = db.query_row("SELECT decks FROM col", [], |r| r.get(0)).map_err(sqlite_error)?;
+        // Every collection has a "Default" deck (id 1) which is usually empty
+        let name = json::from_str::<HashMap<String, Value>>(&decks)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(id, _)| id != "1")
+            .filter_map(|(_, deck)| deck.get("name").and_then(|n| n.as_str()).map(|n| n.to_string()))
+            .min_by_key(|name| name.len());
+
+        let mut notes = db.prepare("SELECT id, flds, tags FROM notes ORDER BY id").map_err(sqlite_error)?;
+        let notes = notes.query_map([], |r| {
+            let fields: String = r.get(1)?;
+            let tags: String = r.get(2)?;
+            Ok(AnkiNote {
+                id: r.get(0)?,
+                fields: fields.split(FIELD_SEPARATOR).map(|f| f.to_string()).collect(),
+                tags: tags.split_whitespace().map(|t| t.to_string()).collect(),
+                scheduling: None,
+            })
+        }).map_err(sqlite_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(sqlite_error)?;
+
+        let mut reviews = db.prepare(
+            "SELECT c.nid, r.id, r.ease, r.ivl, r.factor, r.time, r.lastIvl FROM revlog r
+             JOIN cards c ON c.id = r.cid ORDER BY r.id").map_err(sqlite_error)?;
+        let reviews = reviews.query_map([], |r| {
+            // Review log ids are the epoch milliseconds at which the answer was given
+            let ts: i64 = r.get(1)?;
+            Ok(AnkiReview {
+                note_id: r.get(0)?,
+                ts: Utc.timestamp_millis_opt(ts).single().unwrap_or_else(Utc::now),
+                ease: r.get(2)?,
+                interval: r.get(3)?,
+                factor: r.get(4)?,
+                time: r.get(5)?,
+                last_interval: r.get(6)?,
+            })
+        }).map_err(sqlite_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(sqlite_error)?;
+
+        Ok(Self {
+            name,
+            notes,
+            media: vec![],
+            reviews,
+        })
+    }
+
+    /// Writes this package as an `.apkg` file holding a single deck of "Front"/"Back" notes.
+    /// `scratch` is a directory the collection can be built in.
+    pub fn write(&self, scratch: &Path) -> Result<Vec<u8>, String> {
+        let now = Utc::now();
+        // Anki counts due days of review cards from the day the collection was created
+        let created = Utc.from_utc_datetime(&now.date_naive().and_hms_opt(0, 0, 0).unwrap());
+        let id = now.timestamp_millis();
+        let (model_id, deck_id) = (id, id + 1);
+        let name = self.name.clone().unwrap_or_else(|| "Memra".to_string());
+
+        let models = json!({
+            model_id.to_string(): {
+                "id": model_id, "name": "Memra Basic", "type": 0, "mod": now.timestamp(), "usn": -1,
+                "sortf": 0, "did": deck_id, "tags": [], "vers": [], "req": [[0, "any", [0]]],
+                "flds": [
+                    { "name": "Front", "ord": 0, "sticky": false, "rtl": false, "font": "Arial", "size": 20, "media": [] },
+                    { "name": "Back", "ord": 1, "sticky": false, "rtl": false, "font": "Arial", "size": 20, "media": [] },
+                ],
+                "tmpls": [{
+                    "name": "Card 1", "ord": 0, "did": null, "bqfmt": "", "bafmt": "",
+                    "qfmt": "{{Front}}", "afmt": "{{FrontSide}}<hr id=answer>{{Back}}",
+                }],
+                "css": ".card { font-family: arial; font-size: 20px; text-align: center; }",
+                "latexPre": "\\documentclass[12pt]{article}\n\\begin{document}\n",
+                "latexPost": "\\end{document}",
+            }
+        });
+        let deck = |id: i64, name: &str| json!({
+            "id": id, "name": name, "mod": now.timestamp(), "usn": -1, "desc": "", "dyn": 0, "conf": 1,
+            "collapsed": false, "extendNew": 10, "extendRev": 50,
+            "newToday": [0, 0], "revToday": [0, 0], "lrnToday": [0, 0], "timeToday": [0, 0],
+        });
+        let decks = json!({ "1": deck(1, "Default"), deck_id.to_string(): deck(deck_id, &name) });
+        let dconf = json!({ "1": {
+            "id": 1, "name": "Default", "mod": 0, "usn": 0, "maxTaken": 60, "autoplay": true, "timer": 0,
+            "replayq": true, "dyn": false,
+            "new": { "delays": [1, 10], "ints": [1, 4, 7], "initialFactor": 2500, "order": 1, "perDay": 20, "bury": true },
+            "rev": { "perDay": 200, "ease4": 1.3, "fuzz": 0.05, "maxIvl": 36500, "ivlFct": 1, "bury": true },
+            "lapse": { "delays": [10], "mult": 0, "minInt": 1, "leechFails": 8, "leechAction": 0 },
+        }});
+        let conf = json!({ "curDeck": deck_id, "curModel": model_id.to_string(), "nextPos": self.notes.len() + 1 });
+
+        let collection_path = scratch.join("collection.anki2");
+        let db = rusqlite::Connection::open(&collection_path).map_err(write_error)?;
+        db.execute_batch(COLLECTION_SCHEMA).map_err(write_error)?;
+        db.execute(
+            "INSERT INTO col VALUES (1, ?1, ?2, ?3, 11, 0, 0, 0, ?4, ?5, ?6, ?7, '{}')",
+            params![created.timestamp(), now.timestamp(), id, conf.to_string(), models.to_string(), decks.to_string(), dconf.to_string()],
+        ).map_err(write_error)?;
+
+        for (position, note) in self.notes.iter().enumerate() {
+            let (note_id, card_id) = (id + position as i64, id + position as i64);
+            // Anki fills in the duplicate checksum (csum) itself when checking the database
+            db.execute(
+                "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, ?5, ?6, ?7, 0, 0, '')",
+                params![note_id, format!("memra{}", note_id), model_id, now.timestamp(), format!(" {} ", note.tags.join(" ")),
+                        note.fields.join(&FIELD_SEPARATOR.to_string()), note.fields.first().cloned().unwrap_or_default()],
+            ).map_err(write_error)?;
+
+            // New cards are due by position; review cards by day relative to the collection creation
+            let (kind, due, interval, factor, reps, lapses) = match &note.scheduling {
+                Some(s) if s.interval > 0 => (2, (s.due - created).num_days(), s.interval, (s.ease * 1000.0) as i32, s.repetitions, s.lapses),
+                _ => (0, position as i64, 0, 0, 0, 0),
+            };
+            db.execute(
+                "INSERT INTO cards VALUES (?1, ?2, ?3, 0, ?4, -1, ?5, ?5, ?6, ?7, ?8, ?9, ?10, 0, 0, 0, 0, '')",
+                params![card_id, note_id, deck_id, now.timestamp(), kind, due, interval, factor, reps, lapses],
+            ).map_err(write_error)?;
+        }
+        db.close().map_err(|(_, e)| write_error(e))?;
+
+        let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+        archive.start_file("collection.anki2", options).map_err(write_error)?;
+        io::copy(&mut File::open(&collection_path).map_err(write_error)?, &mut archive).map_err(write_error)?;
+
+        let mut index = HashMap::new();
+        for (entry, (name, data)) in self.media.iter().enumerate() {
+            archive.start_file(entry.to_string(), options).map_err(write_error)?;
+            archive.write_all(data).map_err(write_error)?;
+            index.insert(entry.to_string(), name.clone());
+        }
+        archive.start_file("media", options).map_err(write_error)?;
+        archive.write_all(json::to_string(&index).map_err(write_error)?.as_bytes()).map_err(write_error)?;
+
+        Ok(archive.finish().map_err(write_error)?.into_inner())
+    }
+}
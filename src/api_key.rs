@@ -0,0 +1,119 @@
+use chrono::{Duration, Utc};
+use rand_core::{OsRng, RngCore};
+use rocket::http::Status;
+use rocket::response::status::{Created, Custom};
+use rocket::serde::{Deserialize, Serialize, json::Json};
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx;
+use super::Db;
+use super::auth::{self, AuthenticatedUser};
+use super::models::*;
+use super::session;
+
+/// Start of every key, so leaked keys are easy to recognize
+const KEY_PREFIX: &str = "memra_";
+/// Longest lifetime in days a key can be given; keys without one never expire
+const MAX_EXPIRY_DAYS: i64 = 3650;
+
+fn error(_: sqlx::Error) -> Custom<String> {
+    Custom(
+        Status::InternalServerError,
+        "Could not update API keys. Please try again.".to_string(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyRequest {
+    name: String,
+    scopes: Vec<String>,
+    /// Days until the key expires; keys without one stay valid until revoked
+    expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NewApiKey {
+    /// The whole key, which is only shown once
+    key: String,
+    #[serde(flatten)]
+    api_key: ApiKey,
+}
+
+#[post("/api_keys", data = "<request>")]
+pub async fn create_api_key(mut db: Connection<Db>, user: AuthenticatedUser, request: Json<ApiKeyRequest>) -> Result<Created<Json<NewApiKey>>, Custom<String>> {
+    let request = request.into_inner();
+
+    if request.scopes.is_empty() {
+        return Err(Custom(
+            Status::BadRequest,
+            "An API key needs at least one scope.".to_string(),
+        ));
+    }
+
+    if let Some(scope) = request.scopes.iter().find(|s| !auth::is_scope(s)) {
+        return Err(Custom(
+            Status::BadRequest,
+            format!("Unknown scope {}.", scope),
+        ));
+    }
+
+    if request.expires_in_days.is_some_and(|days| days < 1) {
+        return Err(Custom(
+            Status::BadRequest,
+            "API keys must be valid for at least a day.".to_string(),
+        ));
+    }
+
+    if request.expires_in_days.is_some_and(|days| days > MAX_EXPIRY_DAYS) {
+        return Err(Custom(
+            Status::BadRequest,
+            format!("API keys can be valid for at most {} days.", MAX_EXPIRY_DAYS),
+        ));
+    }
+
+    let mut id = [0u8; 4];
+    OsRng.fill_bytes(&mut id);
+    let prefix = format!("{}{}", KEY_PREFIX, id.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    let key = format!("{}.{}", prefix, session::generate_secret());
+    let now = Utc::now();
+
+    let api_key = ApiKey::new(
+        user.id(),
+        request.name,
+        prefix,
+        session::digest(&key),
+        request.scopes,
+        now,
+        None,
+        request.expires_in_days.map(|days| now + Duration::days(days)),
+        None,
+    ).save_in(&mut *db).await.map_err(error)?;
+
+    Ok(Created::new("/api/users/api_keys").body(Json(NewApiKey { key, api_key })))
+}
+
+#[get("/api_keys")]
+pub async fn list_api_keys(mut db: Connection<Db>, user: AuthenticatedUser) -> Result<Json<Vec<ApiKey>>, Custom<String>> {
+    let keys = sqlx::query(format!(
+            "SELECT * FROM {} WHERE user_id = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > now()) ORDER BY created_at DESC",
+            ApiKey::table()).as_str())
+        .bind(user.id())
+        .fetch_all(&mut *db)
+        .await
+        .map_err(error)?;
+
+    Ok(Json(keys.into_iter().map(ApiKey::from).collect()))
+}
+
+#[delete("/api_keys/<id>")]
+pub async fn revoke_api_key(mut db: Connection<Db>, user: AuthenticatedUser, id: i32) -> Result<Option<()>, Custom<String>> {
+    let revoked = sqlx::query(format!("UPDATE {} SET revoked_at = now() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL", ApiKey::table()).as_str())
+        .bind(id)
+        .bind(user.id())
+        .execute(&mut *db)
+        .await
+        .map_err(error)?
+        .rows_affected();
+
+    Ok((revoked == 1).then_some(()))
+}
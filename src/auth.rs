@@ -1,62 +1,535 @@
+use super::db::Db;
 use super::models;
+use super::session;
 use chrono::{Duration, Utc};
 use jsonwebtoken::{
-    decode, encode, errors::ErrorKind, DecodingKey, EncodingKey, Header, Validation,
+    decode, decode_header, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation,
 };
 use lazy_static::lazy_static;
+use rand_core::{OsRng, RngCore};
 use rocket::{
-    http::Status,
+    fairing::AdHoc,
+    http::{Method, Status},
     request::{FromRequest, Outcome},
-    response::status::Custom
+    outcome::try_outcome,
+    response::status::Custom,
+    Config,
 };
-use serde::{Deserialize, Serialize};
+use rocket_db_pools::{sqlx::{self, PgConnection, PgPool}, Connection, Database};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
 
 const BEARER: &str = "Bearer ";
+const API_KEY: &str = "ApiKey ";
 const AUTHORIZATION: &str = "Authorization";
 
-/// Key used for symmetric token encoding
-const SECRET: &str = "secret";
+/// Key used for symmetric token encoding when none is configured, outside of the release profile
+const DEVELOPMENT_SECRET: &str = "secret";
+
+/// Seconds a revocation check is cached before the database is asked again
+const REVOCATION_CACHE_SECONDS: u64 = 60;
+/// Number of cached revocation checks above which stale ones are dropped
+const REVOCATION_CACHE_SIZE: usize = 10_000;
+
+/// Areas of the API that keys can be scoped to, by the first segment of their paths.
+/// `<area>:read` allows `GET` requests, `<area>:write` allows all of them.
+const SCOPE_AREAS: &[(&str, &str)] = &[
+    ("course", "courses"),
+    ("deck", "decks"),
+    ("import", "decks"),
+    ("card", "cards"),
+    ("history", "history"),
+    ("study", "study"),
+    ("stats", "stats"),
+    ("settings", "settings"),
+    ("notification", "notifications"),
+    ("addon", "addons"),
+];
 
 lazy_static! {
-    /// Time before token expires (aka exp claim)
-    static ref TOKEN_EXPIRATION: Duration = Duration::days(1);
+    /// Time before token expires (aka exp claim) unless `auth.token_lifetime` is set
+    pub(crate) static ref TOKEN_EXPIRATION: Duration = Duration::minutes(15);
+    /// Time an unused session stays valid unless `auth.refresh_lifetime` is set
+    static ref REFRESH_EXPIRATION: Duration = Duration::days(30);
+}
+
+/// What a user may do besides managing their own rows. Each role includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    User,
+    /// May read, change and delete anyone's rows
+    Moderator,
+    /// May also change roles
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::User, Role::Moderator, Role::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Role> {
+        Role::ALL.into_iter().find(|r| r.as_str() == name)
+    }
+
+    /// Names of this role and every role it includes, as carried in tokens
+    fn names(&self) -> Vec<String> {
+        Role::ALL.into_iter().filter(|r| r <= self).map(|r| r.as_str().to_string()).collect()
+    }
 }
 
 // Used when decoding a token to `AuthenticatedUser`
 #[derive(Debug)]
 pub enum AuthenticationError {
     Missing,
-    Decoding(String),
+    Decoding,
     Expired,
+    Revoked,
+    Unverified,
+    InsufficientScope,
+    InsufficientRole,
+    Misconfigured,
+    Database,
 }
 
-// Only the `exp` claim (field) is required. Consult the `jsonwebtoken` documentation for other claims that can be validated.
-#[derive(Serialize, Deserialize)]
-pub struct AuthenticatedUser {
-    pub data: models::User,
-    exp: usize,
+/// A signing or verification key as written in the `auth` section of the Rocket configuration.
+/// HMAC algorithms use `secret`, the others read PEM files.
+#[derive(Deserialize)]
+struct KeySettings {
+    key_id: Option<String>,
+    algorithm: Option<String>,
+    secret: Option<String>,
+    private_key: Option<PathBuf>,
+    public_key: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct AuthSettings {
+    #[serde(flatten)]
+    key: KeySettings,
+    issuer: Option<String>,
+    audience: Option<String>,
+    /// Lifetime of issued access tokens, in seconds
+    token_lifetime: Option<i64>,
+    /// Lifetime of refresh tokens, in seconds
+    refresh_lifetime: Option<i64>,
+    /// Whether creating content requires a verified email address
+    #[serde(default)]
+    require_verified_email: bool,
+    /// Keys that tokens signed before a rotation are still verified with
+    #[serde(default)]
+    previous_keys: Vec<KeySettings>,
+}
+
+fn read_pem(path: &Option<PathBuf>, name: &str) -> Result<Vec<u8>, String> {
+    let path = path.as_deref().ok_or_else(|| format!("auth.{} is required for this algorithm", name))?;
+    std::fs::read(Path::new(path)).map_err(|e| format!("could not read {}: {}", path.display(), e))
+}
+
+impl KeySettings {
+    fn algorithm(&self) -> Result<Algorithm, String> {
+        self.algorithm.as_deref().unwrap_or("HS256").parse()
+            .map_err(|_| format!("unsupported algorithm {}", self.algorithm.as_deref().unwrap_or_default()))
+    }
+
+    fn encoding_key(&self, algorithm: Algorithm) -> Result<EncodingKey, String> {
+        let pem = |name| read_pem(&self.private_key, name);
+        match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => return self.secret.as_ref()
+                .map(|secret| EncodingKey::from_secret(secret.as_bytes()))
+                .ok_or_else(|| "auth.secret is required for this algorithm".to_string()),
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+                | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => EncodingKey::from_rsa_pem(&pem("private_key")?),
+            Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(&pem("private_key")?),
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(&pem("private_key")?),
+        }.map_err(|e| format!("invalid private key: {}", e))
+    }
+
+    fn decoding_key(&self, algorithm: Algorithm) -> Result<DecodingKey, String> {
+        let pem = |name| read_pem(&self.public_key, name);
+        match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => return self.secret.as_ref()
+                .map(|secret| DecodingKey::from_secret(secret.as_bytes()))
+                .ok_or_else(|| "auth.secret is required for this algorithm".to_string()),
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+                | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => DecodingKey::from_rsa_pem(&pem("public_key")?),
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem("public_key")?),
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem("public_key")?),
+        }.map_err(|e| format!("invalid public key: {}", e))
+    }
+}
+
+/// Keys and claims used to issue and verify tokens, managed by `AuthConfig::fairing()`
+pub struct AuthConfig {
+    header: Header,
+    encoding_key: EncodingKey,
+    /// Verification keys by `kid`; tokens without one are checked against the current key
+    decoding_keys: HashMap<Option<String>, (DecodingKey, Validation)>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    lifetime: Duration,
+    refresh_lifetime: Duration,
+    require_verified_email: bool,
+    revocations: Revocations,
+}
+
+/// Recent revocation checks by token id, so that most requests don't query `token_revocations`
+#[derive(Default)]
+struct Revocations {
+    checked: Mutex<HashMap<String, CheckedToken>>,
+}
+
+struct CheckedToken {
+    user_id: i32,
+    session_id: Option<i32>,
+    revoked: bool,
+    at: Instant,
+}
+
+impl Revocations {
+    fn get(&self, jti: &str) -> Option<bool> {
+        let checked = self.checked.lock().unwrap();
+        checked.get(jti)
+            .filter(|c| c.at.elapsed().as_secs() < REVOCATION_CACHE_SECONDS)
+            .map(|c| c.revoked)
+    }
+
+    fn insert(&self, jti: String, user_id: i32, session_id: Option<i32>, revoked: bool) {
+        let mut checked = self.checked.lock().unwrap();
+        if checked.len() >= REVOCATION_CACHE_SIZE {
+            checked.retain(|_, c| c.at.elapsed().as_secs() < REVOCATION_CACHE_SECONDS);
+        }
+        checked.insert(jti, CheckedToken { user_id, session_id, revoked, at: Instant::now() });
+    }
+
+    fn revoke_session(&self, session_id: i32) {
+        let mut checked = self.checked.lock().unwrap();
+        for c in checked.values_mut().filter(|c| c.session_id == Some(session_id)) {
+            c.revoked = true;
+        }
+    }
+
+    fn revoke_user(&self, user_id: i32) {
+        let mut checked = self.checked.lock().unwrap();
+        for c in checked.values_mut().filter(|c| c.user_id == user_id) {
+            c.revoked = true;
+        }
+    }
+}
+
+/// Revokes every token issued to a user so far, keeping the revocation for as long as tokens of
+/// `lifetime` stay valid. Servers that already checked a token may accept it until their cache expires.
+pub(crate) async fn revoke_user_tokens(conn: &mut PgConnection, user_id: i32, lifetime: Duration) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    sqlx::query(format!("DELETE FROM {} WHERE expires_at < $1", models::Revocation::table()).as_str())
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+    models::Revocation::new(user_id, None, now, now + lifetime)
+        .save_in(conn).await?;
+    Ok(())
 }
 
-impl Into<models::User> for AuthenticatedUser {
-    fn into(self) -> models::User {
-        self.data
+impl AuthConfig {
+    fn from_settings(settings: AuthSettings) -> Result<Self, String> {
+        let validation = |algorithm| {
+            let mut validation = Validation::new(algorithm);
+            if let Some(issuer) = &settings.issuer {
+                validation.set_issuer(&[issuer]);
+            }
+            if let Some(audience) = &settings.audience {
+                validation.set_audience(&[audience]);
+            }
+            validation
+        };
+
+        let algorithm = settings.key.algorithm()?;
+        let mut decoding_keys = HashMap::new();
+        decoding_keys.insert(None, (settings.key.decoding_key(algorithm)?, validation(algorithm)));
+        if settings.key.key_id.is_some() {
+            decoding_keys.insert(settings.key.key_id.clone(), (settings.key.decoding_key(algorithm)?, validation(algorithm)));
+        }
+        for key in &settings.previous_keys {
+            if key.key_id.is_none() {
+                return Err("auth.previous_keys must each have a key_id".to_string());
+            }
+            let algorithm = key.algorithm()?;
+            decoding_keys.insert(key.key_id.clone(), (key.decoding_key(algorithm)?, validation(algorithm)));
+        }
+
+        let mut header = Header::new(algorithm);
+        header.kid = settings.key.key_id.clone();
+
+        Ok(AuthConfig {
+            header,
+            encoding_key: settings.key.encoding_key(algorithm)?,
+            decoding_keys,
+            issuer: settings.issuer,
+            audience: settings.audience,
+            lifetime: settings.token_lifetime.map(Duration::seconds).unwrap_or(*TOKEN_EXPIRATION),
+            refresh_lifetime: settings.refresh_lifetime.map(Duration::seconds).unwrap_or(*REFRESH_EXPIRATION),
+            require_verified_email: settings.require_verified_email,
+            revocations: Revocations::default(),
+        })
+    }
+
+    async fn is_revoked(&self, claims: &AuthenticatedUser, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        if let Some(revoked) = self.revocations.get(&claims.jti) {
+            return Ok(revoked);
+        }
+
+        // Tokens also stop working once the session they were issued for is revoked
+        let revoked: bool = sqlx::query_scalar(format!(
+                "SELECT EXISTS (SELECT 1 FROM {} WHERE user_id = $1 AND (jti = $2 OR (jti IS NULL AND revoked_at >= to_timestamp($3))))
+                 OR EXISTS (SELECT 1 FROM {} WHERE id = $4 AND revoked_at IS NOT NULL)",
+                models::Revocation::table(), models::Session::table()).as_str())
+            .bind(claims.id())
+            .bind(&claims.jti)
+            .bind(claims.iat)
+            .bind(claims.sid)
+            .fetch_one(pool)
+            .await?;
+
+        self.revocations.insert(claims.jti.clone(), claims.id(), claims.sid, revoked);
+        Ok(revoked)
+    }
+
+    /// Revokes a single token, e.g. on logout
+    pub(crate) async fn revoke(&self, conn: &mut PgConnection, claims: &AuthenticatedUser) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+        let expires_at = now + Duration::seconds(claims.exp as i64 - now.timestamp());
+        models::Revocation::new(claims.id(), Some(claims.jti.clone()), now, expires_at)
+            .save_in(conn).await?;
+
+        self.revocations.insert(claims.jti.clone(), claims.id(), claims.sid, true);
+        Ok(())
+    }
+
+    /// Stops accepting the tokens of a session that was just revoked, which were checked recently
+    pub(crate) fn revoke_session(&self, session_id: i32) {
+        self.revocations.revoke_session(session_id);
+    }
+
+    /// Revokes every token issued to a user so far
+    pub(crate) async fn revoke_user(&self, conn: &mut PgConnection, user_id: i32) -> Result<(), sqlx::Error> {
+        revoke_user_tokens(conn, user_id, self.lifetime).await?;
+        self.revocations.revoke_user(user_id);
+        Ok(())
+    }
+
+    /// Signs claims with the current key. Besides access tokens, this is used for links sent by email.
+    pub(crate) fn encode<T: Serialize>(&self, claims: &T) -> Result<String, Custom<String>> {
+        // Construct and return JWT using `jsonwebtoken`
+        encode(&self.header, claims, &self.encoding_key)
+            .map_err(|e| Custom(Status::BadRequest, e.to_string()))
+    }
+
+    /// Verifies a token signed by `encode()`, with the current or a previous key
+    pub(crate) fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<T, AuthenticationError> {
+        // The `kid` header picks the verification key, so tokens signed before a key rotation stay valid
+        let kid = decode_header(token)
+            .map_err(|e| {
+                debug!("Could not decode token header: {}", e);
+                AuthenticationError::Decoding
+            })?
+            .kid;
+        let (key, validation) = self.decoding_keys.get(&kid)
+            .ok_or_else(|| {
+                debug!("Token signed with unknown key id {:?}", kid);
+                AuthenticationError::Decoding
+            })?;
+
+        // Use `jsonwebtoken` to get the claims from a JWT
+        decode::<T>(token, key, validation)
+            .map(|token| token.claims)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => AuthenticationError::Expired,
+                _ => {
+                    debug!("Could not decode token: {}", e);
+                    AuthenticationError::Decoding
+                }
+            })
+    }
+
+    pub fn issuer(&self) -> Option<String> {
+        self.issuer.clone()
+    }
+
+    pub fn audience(&self) -> Option<String> {
+        self.audience.clone()
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    pub fn refresh_lifetime(&self) -> Duration {
+        self.refresh_lifetime
     }
+
+    /// Reads the `auth` configuration on ignite. Launch is aborted if it is invalid, or if no key
+    /// is configured in the release profile; other profiles fall back to a development secret.
+    pub fn fairing() -> AdHoc {
+        AdHoc::try_on_ignite("Authentication", |rocket| async move {
+            let figment = rocket.figment();
+            let mut settings = match figment.find_value("auth") {
+                Ok(_) => match figment.extract_inner::<AuthSettings>("auth") {
+                    Ok(settings) => settings,
+                    Err(e) => {
+                        error!("Invalid auth configuration: {}", e);
+                        return Err(rocket);
+                    }
+                },
+                Err(_) => AuthSettings {
+                    key: KeySettings { key_id: None, algorithm: None, secret: None, private_key: None, public_key: None },
+                    issuer: None,
+                    audience: None,
+                    token_lifetime: None,
+                    refresh_lifetime: None,
+                    require_verified_email: false,
+                    previous_keys: vec![],
+                },
+            };
+
+            if settings.key.secret.is_none() && settings.key.private_key.is_none() {
+                if figment.profile() == Config::RELEASE_PROFILE {
+                    error!("No token signing key configured. Set auth.secret or auth.private_key.");
+                    return Err(rocket);
+                }
+                warn!("No token signing key configured, using an insecure development secret.");
+                settings.key.secret = Some(DEVELOPMENT_SECRET.to_string());
+            }
+
+            match AuthConfig::from_settings(settings) {
+                Ok(config) => Ok(rocket.manage(config)),
+                Err(e) => {
+                    error!("Invalid auth configuration: {}", e);
+                    Err(rocket)
+                }
+            }
+        })
+    }
+}
+
+// Only the `exp` claim (field) is required by `jsonwebtoken`; `iss` and `aud` are checked when configured.
+// Profile data is left out of the claims and loaded on demand with `load()`.
+#[derive(Serialize, Deserialize)]
+pub struct AuthenticatedUser {
+    /// Id of the user
+    sub: i32,
+    username: String,
+    roles: Vec<String>,
+    exp: usize,
+    /// Issue time with milliseconds, so revocations and new logins in the same second are told apart
+    iat: f64,
+    /// Unique id of the token, used to revoke it
+    jti: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aud: Option<String>,
+    /// Session the token was issued for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sid: Option<i32>,
+    /// Scopes of the API key the request was made with. Tokens aren't limited to any.
+    #[serde(skip)]
+    scopes: Option<Vec<String>>,
 }
 
 impl AuthenticatedUser {
     pub fn id(&self) -> i32 {
-        self.data.id.unwrap()
+        self.sub
+    }
+
+    /// Reads the current state of the user from the database
+    pub async fn load(&self, db: Connection<Db>) -> (Option<models::User>, Connection<Db>) {
+        models::User::read(self.sub, db).await
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn session_id(&self) -> Option<i32> {
+        self.sid
+    }
+
+    /// Highest role in the token
+    pub fn role(&self) -> Role {
+        self.roles.iter().filter_map(|r| Role::parse(r)).max().unwrap_or(Role::User)
+    }
+
+    /// Whether the user may act on rows of others
+    pub fn is_staff(&self) -> bool {
+        self.role() >= Role::Moderator
     }
 
-    pub(crate) fn from_user(user: models::User) -> Self {
+    pub(crate) fn from_user(user: &models::User, session_id: i32) -> Self {
         Self {
-            data: user,
+            sub: user.id.unwrap(),
+            username: user.username.clone(),
+            roles: Role::parse(&user.role).unwrap_or(Role::User).names(),
             exp: 0,
+            iat: 0.0,
+            jti: String::new(),
+            iss: None,
+            aud: None,
+            sid: Some(session_id),
+            scopes: None,
+        }
+    }
+
+    /// Looks up the user an API key belongs to, recording that the key was used
+    async fn from_api_key(key: &str, pool: &PgPool) -> Result<Self, AuthenticationError> {
+        let row: Option<(i32, String, String, Vec<String>)> = sqlx::query_as(format!(
+                "UPDATE {} k SET last_used_at = now() FROM {} u \
+                 WHERE k.key_hash = $1 AND k.revoked_at IS NULL AND (k.expires_at IS NULL OR k.expires_at > now()) \
+                 AND u.id = k.user_id AND NOT u.disabled \
+                 RETURNING u.id, u.username, u.role, k.scopes",
+                models::ApiKey::table(), models::User::table()).as_str())
+            .bind(session::digest(key))
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| {
+                error!("Could not look up API key: {}", e);
+                AuthenticationError::Database
+            })?;
+        let (id, username, role, scopes) = row.ok_or(AuthenticationError::Decoding)?;
+
+        Ok(Self {
+            sub: id,
+            username,
+            roles: Role::parse(&role).unwrap_or(Role::User).names(),
+            exp: 0,
+            iat: 0.0,
+            jti: String::new(),
+            iss: None,
+            aud: None,
+            sid: None,
+            scopes: Some(scopes),
+        })
+    }
+
+    fn allows(&self, scope: &str) -> bool {
+        match &self.scopes {
+            None => true,
+            Some(scopes) => scopes.iter().any(|s| s == scope
+                || scope.strip_suffix(":read").is_some_and(|area| *s == format!("{}:write", area))),
         }
     }
 
     /// Create a `AuthenticatedUser` from a 'Bearer <token>' value
-    fn from_authorization(value: &str) -> Result<Self, AuthenticationError> {
+    fn from_authorization(value: &str, config: &AuthConfig) -> Result<Self, AuthenticationError> {
         let token = value.strip_prefix(BEARER);
 
         if token.is_none() {
@@ -66,40 +539,27 @@ impl AuthenticatedUser {
         // Safe to unwrap as we just confirmed it is not none
         let token = token.unwrap();
 
-        // Use `jsonwebtoken` to get the claims from a JWT
-        // Consult the `jsonwebtoken` documentation for using other algorithms and validations (the default validation just checks the expiration claim)
-        let token = decode::<AuthenticatedUser>(
-            token,
-            &DecodingKey::from_secret(SECRET.as_ref()),
-            &Validation::default(),
-        )
-        .map_err(|e| match e.kind() {
-            ErrorKind::ExpiredSignature => AuthenticationError::Expired,
-            _ => AuthenticationError::Decoding(e.to_string()),
-        })?;
-
-        Ok(token.claims)
+        config.decode::<AuthenticatedUser>(token)
     }
 
-    /// Converts this claims into a token string
-    pub(crate) fn to_token(mut self) -> Result<String, Custom<String>> {
-        let expiration = Utc::now()
-            .checked_add_signed(*TOKEN_EXPIRATION)
+    /// Converts these claims into a token string
+    pub(crate) fn into_token(mut self, config: &AuthConfig) -> Result<String, Custom<String>> {
+        let now = Utc::now();
+        let expiration = now
+            .checked_add_signed(config.lifetime)
             .expect("failed to create an expiration time")
             .timestamp();
 
-        self.exp = expiration as usize;
+        let mut jti = [0u8; 16];
+        OsRng.fill_bytes(&mut jti);
 
-        // Construct and return JWT using `jsonwebtoken`
-        // Consult the `jsonwebtoken` documentation for using other algorithms and asymmetric keys
-        let token = encode(
-            &Header::default(),
-            &self,
-            &EncodingKey::from_secret(SECRET.as_ref()),
-        )
-        .map_err(|e| Custom(Status::BadRequest, e.to_string()))?;
+        self.exp = expiration as usize;
+        self.iat = now.timestamp_millis() as f64 / 1000.0;
+        self.jti = jti.iter().map(|b| format!("{:02x}", b)).collect();
+        self.iss = config.issuer.clone();
+        self.aud = config.audience.clone();
 
-        Ok(token)
+        config.encode(&self)
     }
 }
 
@@ -115,6 +575,74 @@ impl User {
             User::Guest => None
         }
     }
+
+    pub fn is_staff(&self) -> bool {
+        match self {
+            User::Authenticated(u) => u.is_staff(),
+            User::Guest => false
+        }
+    }
+}
+
+/// Whether `scope` can be given to an API key
+pub(crate) fn is_scope(scope: &str) -> bool {
+    scope.split_once(':').is_some_and(|(area, access)|
+        SCOPE_AREAS.iter().any(|(_, a)| *a == area) && (access == "read" || access == "write"))
+}
+
+/// Scope an API key needs for a request. Account routes under `/api/users` can't be used with
+/// API keys at all, except for statistics.
+fn required_scope(request: &rocket::Request<'_>) -> Option<String> {
+    scope_for(request.uri().path().segments(), request.method())
+}
+
+fn scope_for<'a>(mut segments: impl Iterator<Item = &'a str>, method: Method) -> Option<String> {
+    let mut segment = segments.next()?;
+    if segment == "api" {
+        segment = segments.next()?;
+    }
+    if segment == "users" {
+        segment = segments.next().filter(|s| *s == "stats")?;
+    }
+
+    let area = SCOPE_AREAS.iter().find(|(s, _)| *s == segment)?.1;
+    let access = match method {
+        Method::Get | Method::Head => "read",
+        _ => "write",
+    };
+    Some(format!("{}:{}", area, access))
+}
+
+/// Decodes the Authorization header of a request and makes sure its token wasn't revoked,
+/// or that its API key is active and has the scope the request needs
+async fn authenticate(request: &rocket::Request<'_>, value: &str) -> Result<AuthenticatedUser, (Status, AuthenticationError)> {
+    let config = request.rocket().state::<AuthConfig>()
+        .ok_or((Status::InternalServerError, AuthenticationError::Misconfigured))?;
+    let db = Db::fetch(request.rocket())
+        .ok_or((Status::InternalServerError, AuthenticationError::Misconfigured))?;
+
+    if let Some(key) = value.strip_prefix(API_KEY) {
+        let user = AuthenticatedUser::from_api_key(key, &db.0).await.map_err(|e| match e {
+            AuthenticationError::Database => (Status::InternalServerError, e),
+            _ => (Status::Forbidden, e),
+        })?;
+        return match required_scope(request) {
+            Some(scope) if user.allows(&scope) => Ok(user),
+            _ => Err((Status::Forbidden, AuthenticationError::InsufficientScope)),
+        };
+    }
+
+    let claims = AuthenticatedUser::from_authorization(value, config)
+        .map_err(|e| (Status::Forbidden, e))?;
+
+    match config.is_revoked(&claims, &db.0).await {
+        Err(e) => {
+            error!("Could not check token revocation: {}", e);
+            Err((Status::InternalServerError, AuthenticationError::Database))
+        }
+        Ok(true) => Err((Status::Forbidden, AuthenticationError::Revoked)),
+        Ok(false) => Ok(claims),
+    }
 }
 
 // Rocket specific request guard implementations
@@ -125,8 +653,8 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
     async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
         match request.headers().get_one(AUTHORIZATION) {
             None => Outcome::Failure((Status::Forbidden, AuthenticationError::Missing)),
-            Some(value) => match AuthenticatedUser::from_authorization(value) {
-                Err(e) => Outcome::Failure((Status::Forbidden, e)),
+            Some(value) => match authenticate(request, value).await {
+                Err(e) => Outcome::Failure(e),
                 Ok(claims) => Outcome::Success(claims),
             },
         }
@@ -140,11 +668,139 @@ impl<'r> FromRequest<'r> for User {
     async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
         match request.headers().get_one(AUTHORIZATION) {
             None => Outcome::Success(User::Guest),
-            Some(value) => match AuthenticatedUser::from_authorization(value) {
-                Err(e) => Outcome::Failure((Status::Forbidden, e)),
+            Some(value) => match authenticate(request, value).await {
+                Err(e) => Outcome::Failure(e),
                 Ok(claims) => Outcome::Success(User::Authenticated(claims)),
             },
         }
     }
 }
 
+
+/// An `AuthenticatedUser` who verified their email address, if `auth.require_verified_email` is set
+pub struct VerifiedUser(pub AuthenticatedUser);
+
+impl Deref for VerifiedUser {
+    type Target = AuthenticatedUser;
+
+    fn deref(&self) -> &AuthenticatedUser {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for VerifiedUser {
+    type Error = AuthenticationError;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = try_outcome!(request.guard::<AuthenticatedUser>().await);
+        let config = match request.rocket().state::<AuthConfig>() {
+            Some(config) => config,
+            None => return Outcome::Failure((Status::InternalServerError, AuthenticationError::Misconfigured)),
+        };
+
+        if !config.require_verified_email {
+            return Outcome::Success(VerifiedUser(user));
+        }
+
+        let db = match Db::fetch(request.rocket()) {
+            Some(db) => db,
+            None => return Outcome::Failure((Status::InternalServerError, AuthenticationError::Misconfigured)),
+        };
+        let verified: Result<Option<Option<bool>>, _> = sqlx::query_scalar(format!("SELECT verified FROM {} WHERE id = $1", models::User::table()).as_str())
+            .bind(user.id())
+            .fetch_optional(&db.0)
+            .await;
+
+        match verified {
+            Err(e) => {
+                error!("Could not check email verification: {}", e);
+                Outcome::Failure((Status::InternalServerError, AuthenticationError::Database))
+            }
+            Ok(Some(Some(true))) => Outcome::Success(VerifiedUser(user)),
+            Ok(_) => Outcome::Failure((Status::Forbidden, AuthenticationError::Unverified)),
+        }
+    }
+}
+
+/// Only lets users with at least the role `MINIMUM` (a `Role` as `u8`) through.
+/// Added to routes by `#[requires_role(...)]`.
+pub struct RoleGuard<const MINIMUM: u8>;
+
+#[rocket::async_trait]
+impl<'r, const MINIMUM: u8> FromRequest<'r> for RoleGuard<MINIMUM> {
+    type Error = AuthenticationError;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = try_outcome!(request.guard::<AuthenticatedUser>().await);
+        if (user.role() as u8) < MINIMUM {
+            return Outcome::Failure((Status::Forbidden, AuthenticationError::InsufficientRole));
+        }
+        Outcome::Success(RoleGuard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(method: Method, path: &str) -> Option<String> {
+        scope_for(path.split('/').filter(|s| !s.is_empty()), method)
+    }
+
+    fn key(scopes: &[&str]) -> AuthenticatedUser {
+        AuthenticatedUser {
+            sub: 1,
+            username: "alice".to_string(),
+            roles: Role::User.names(),
+            exp: 0,
+            iat: 0.0,
+            jti: String::new(),
+            iss: None,
+            aud: None,
+            sid: None,
+            scopes: Some(scopes.iter().map(|s| s.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn maps_routes_to_scopes() {
+        assert_eq!(scope(Method::Get, "/api/deck/5").as_deref(), Some("decks:read"));
+        assert_eq!(scope(Method::Head, "/deck").as_deref(), Some("decks:read"));
+        assert_eq!(scope(Method::Put, "/card/3").as_deref(), Some("cards:write"));
+        assert_eq!(scope(Method::Post, "/api/import/apkg").as_deref(), Some("decks:write"));
+        assert_eq!(scope(Method::Post, "/api/study/review").as_deref(), Some("study:write"));
+        assert_eq!(scope(Method::Get, "/notification").as_deref(), Some("notifications:read"));
+    }
+
+    #[test]
+    fn keeps_account_routes_from_keys() {
+        assert_eq!(scope(Method::Get, "/api/users/stats/heatmap").as_deref(), Some("stats:read"));
+        assert_eq!(scope(Method::Get, "/api/users/stats").as_deref(), Some("stats:read"));
+        assert_eq!(scope(Method::Get, "/api/users/sessions"), None);
+        assert_eq!(scope(Method::Post, "/api/users/api_keys"), None);
+        assert_eq!(scope(Method::Get, "/api/users/1"), None);
+        assert_eq!(scope(Method::Get, "/api/users"), None);
+        assert_eq!(scope(Method::Get, "/api/unknown"), None);
+        assert_eq!(scope(Method::Get, "/"), None);
+    }
+
+    #[test]
+    fn write_scopes_include_read() {
+        let user = key(&["decks:write", "cards:read"]);
+        assert!(user.allows("decks:read"));
+        assert!(user.allows("decks:write"));
+        assert!(user.allows("cards:read"));
+        assert!(!user.allows("cards:write"));
+        assert!(!user.allows("history:read"));
+    }
+
+    #[test]
+    fn validates_scope_names() {
+        assert!(is_scope("decks:read"));
+        assert!(is_scope("addons:write"));
+        assert!(!is_scope("deck:read"));
+        assert!(!is_scope("decks:delete"));
+        assert!(!is_scope("decks"));
+    }
+}
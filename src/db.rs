@@ -0,0 +1,7 @@
+use rocket_db_pools::{sqlx, Database};
+
+pub type Result<T, E = rocket::response::Debug<sqlx::Error>> = std::result::Result<T, E>;
+
+#[derive(Database)]
+#[database("main")]
+pub struct Db(pub sqlx::PgPool);
@@ -0,0 +1,166 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response, status::Custom};
+use rocket::serde::{Serialize, json};
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx;
+use rocket::futures::TryStreamExt;
+use std::collections::HashMap;
+use std::io::Cursor;
+use super::Db;
+use super::anki::{AnkiNote, AnkiPackage};
+use super::auth::User;
+use super::models::*;
+use super::scheduler::SchedulingState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub enum ExportFormat {
+    Apkg,
+    Csv,
+    Json,
+}
+
+/// A downloadable file
+pub struct Export {
+    content_type: ContentType,
+    filename: String,
+    body: Vec<u8>,
+}
+
+impl<'r> Responder<'r, 'static> for Export {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(self.content_type)
+            .raw_header("Content-Disposition", format!("attachment; filename=\"{}\"", self.filename))
+            .sized_body(self.body.len(), Cursor::new(self.body))
+            .ok()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportedCard {
+    pub front: String,
+    pub back: String,
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduling: Option<SchedulingState>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportedDeck {
+    pub name: String,
+    pub cards: Vec<ExportedCard>,
+}
+
+fn error<E>(_: E) -> Custom<String> {
+    Custom(
+        Status::InternalServerError,
+        "Could not export deck. Please try again.".to_string(),
+    )
+}
+
+fn csv(deck: &ExportedDeck, scheduling: bool) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    let mut header = vec!["front", "back", "tags"];
+    if scheduling {
+        header.extend(["due", "interval", "ease", "repetitions", "lapses"]);
+    }
+    writer.write_record(&header)?;
+
+    for card in &deck.cards {
+        let mut record = vec![card.front.clone(), card.back.clone(), card.tags.join(" ")];
+        if scheduling {
+            match &card.scheduling {
+                Some(s) => record.extend([s.due.to_rfc3339(), s.interval.to_string(), s.ease.to_string(), s.repetitions.to_string(), s.lapses.to_string()]),
+                None => record.extend(vec![String::new(); 5]),
+            }
+        }
+        writer.write_record(&record)?;
+    }
+
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// Exports a deck and its cards, optionally with the requesting user's scheduling state
+#[get("/<id>/export?<format>&<scheduling>")]
+pub async fn export_deck(db: Connection<Db>, user: User, id: i32, format: ExportFormat, scheduling: Option<bool>) -> Result<Export, Custom<String>> {
+    // Same rules as reading the deck
+    let (deck, db) = Deck::find_permitted(id, &Deck::read_scope(user.is_staff()), user.id(), db).await;
+    let deck = match deck.map_err(error)? {
+        Some(deck) => deck,
+        None => return Err(Custom(Status::NotFound, "Deck does not exist.".to_string())),
+    };
+
+    let (cards, mut db) = deck.find_card(db).await;
+
+    let mut history = HashMap::new();
+    if let (Some(true), Some(user_id)) = (scheduling, user.id()) {
+        history = sqlx::query(format!(
+                "SELECT h.* FROM {} h JOIN {} c ON c.id = h.card_id WHERE h.user_id = $1 AND c.deck_id = $2",
+                History::table(), Card::table()).as_str())
+            .bind(user_id)
+            .bind(id)
+            .fetch(&mut *db)
+            .map_ok(|r| {
+                let h = History::from(r);
+                (h.card_id, h.state())
+            })
+            .try_collect::<HashMap<_, _>>()
+            .await
+            .map_err(error)?;
+    }
+
+    let exported = ExportedDeck {
+        name: deck.name.clone(),
+        cards: cards.iter().map(|card| ExportedCard {
+            front: String::from_utf8_lossy(&card.front).to_string(),
+            back: String::from_utf8_lossy(&card.back).to_string(),
+            tags: card.tags.clone(),
+            scheduling: card.id.and_then(|id| history.remove(&id)),
+        }).collect(),
+    };
+
+    let filename: String = deck.name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+
+    match format {
+        ExportFormat::Json => Ok(Export {
+            content_type: ContentType::JSON,
+            filename: format!("{}.json", filename),
+            body: json::to_string(&exported).map_err(error)?.into_bytes(),
+        }),
+        ExportFormat::Csv => Ok(Export {
+            content_type: ContentType::CSV,
+            filename: format!("{}.csv", filename),
+            body: csv(&exported, scheduling.unwrap_or(false)).map_err(error)?,
+        }),
+        ExportFormat::Apkg => {
+            let (media, _db) = deck.find_media(db).await;
+            let package = AnkiPackage {
+                name: Some(exported.name),
+                notes: exported.cards.into_iter().zip(cards.iter()).map(|(exported, card)| AnkiNote {
+                    id: card.id.unwrap_or_default() as i64,
+                    fields: vec![exported.front, exported.back],
+                    tags: exported.tags,
+                    scheduling: exported.scheduling,
+                }).collect(),
+                media: media.into_iter().map(|m| (m.name, m.data)).collect(),
+                reviews: vec![],
+            };
+
+            let body = rocket::tokio::task::spawn_blocking(move || {
+                let scratch = tempfile::tempdir().map_err(|e| e.to_string())?;
+                package.write(scratch.path())
+            }).await.map_err(error)?.map_err(error)?;
+
+            Ok(Export {
+                content_type: ContentType::new("application", "apkg"),
+                filename: format!("{}.apkg", filename),
+                body,
+            })
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use chrono::{DateTime, Duration, Utc};
+use rocket::data::{Data, Limits, ToByteUnit};
+use rocket::fs::TempFile;
+use rocket::futures::TryStreamExt;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::{Serialize, json::Json};
+use rocket::State;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Acquire, PgPool, Row};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use super::Db;
+use super::anki::{AnkiPackage, AnkiReview};
+use super::auth::{AuthenticatedUser, VerifiedUser};
+use super::models::*;
+use super::scheduler::Grade;
+
+/// How long the progress of a finished import stays available
+const JOB_RETENTION: i64 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum ImportStatus {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportJob {
+    pub id: u64,
+    #[serde(skip)]
+    pub user_id: i32,
+    pub status: ImportStatus,
+    /// Number of notes, media files and review log entries to import
+    pub total: usize,
+    pub processed: usize,
+    pub deck_id: Option<i32>,
+    pub error: Option<String>,
+    #[serde(skip)]
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Progress of background imports, shared between request handlers and the import tasks
+#[derive(Clone, Default)]
+pub struct ImportJobs {
+    next_id: Arc<AtomicU64>,
+    jobs: Arc<Mutex<HashMap<u64, ImportJob>>>,
+}
+
+impl ImportJobs {
+    fn create(&self, user_id: i32) -> ImportJob {
+        let job = ImportJob {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            user_id,
+            status: ImportStatus::Queued,
+            total: 0,
+            processed: 0,
+            deck_id: None,
+            error: None,
+            finished_at: None,
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        let expired = Utc::now() - Duration::hours(JOB_RETENTION);
+        jobs.retain(|_, j| j.finished_at.is_none_or(|ts| ts > expired));
+        jobs.insert(job.id, job.clone());
+        job
+    }
+
+    fn update<F: FnOnce(&mut ImportJob)>(&self, id: u64, f: F) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            f(job);
+        }
+    }
+
+    fn finish(&self, id: u64, result: Result<i32, String>) {
+        self.update(id, |job| {
+            match result {
+                Ok(deck_id) => {
+                    job.status = ImportStatus::Finished;
+                    job.processed = job.total;
+                    job.deck_id = Some(deck_id);
+                }
+                Err(e) => {
+                    job.status = ImportStatus::Failed;
+                    job.error = Some(e);
+                }
+            }
+            job.finished_at = Some(Utc::now());
+        });
+    }
+
+    pub fn get(&self, id: u64, user_id: i32) -> Option<ImportJob> {
+        self.jobs.lock().unwrap()
+            .get(&id)
+            .filter(|job| job.user_id == user_id)
+            .cloned()
+    }
+}
+
+fn database_error(_: sqlx::Error) -> String {
+    "Could not save imported cards. Please try again.".to_string()
+}
+
+/// Builds the scheduling state of a card from its Anki review log (oldest entry first)
+fn history_from_anki(user_id: i32, card_id: i32, reviews: &[&AnkiReview]) -> History {
+    let last = reviews.last().unwrap();
+    let mut history = History::unseen(user_id, card_id, last.ts);
+
+    for review in reviews {
+        match review.ease {
+            1 => {
+                history.num_wrong += 1;
+                if history.repetitions > 0 {
+                    history.lapses += 1;
+                }
+                history.repetitions = 0;
+            }
+            4 => {
+                history.num_confident += 1;
+                history.repetitions += 1;
+            }
+            _ => {
+                history.num_correct += 1;
+                history.repetitions += 1;
+            }
+        }
+        history.time_spent += review.time.max(0) as i64;
+    }
+
+    if last.factor > 0 {
+        history.ease = last.factor as f64 / 1000.0;
+    }
+    // Negative intervals are learning steps in seconds
+    if last.interval > 0 {
+        history.interval = last.interval;
+        history.due = last.ts + Duration::days(last.interval as i64);
+    } else {
+        history.due = last.ts + Duration::seconds(-last.interval as i64);
+    }
+    history
+}
+
+async fn import(pool: PgPool, jobs: &ImportJobs, job: u64, user_id: i32, package: AnkiPackage, name: String, with_history: bool) -> Result<i32, String> {
+    let mut tx = pool.begin().await.map_err(database_error)?;
+
+    let deck = Deck::new(user_id, Some(true), name, vec![])
+        .save_in(&mut *tx).await.map_err(database_error)?;
+    let deck_id = deck.id.unwrap();
+
+    let mut cards = HashMap::new();
+    for note in &package.notes {
+        let mut fields = note.fields.iter();
+        let front = fields.next().cloned().unwrap_or_default();
+        let back = fields.next().cloned().unwrap_or_default();
+        let card = Card::new(user_id, deck_id, front.into_bytes(), back.into_bytes(), note.tags.clone())
+            .save_in(&mut *tx).await.map_err(database_error)?;
+        cards.insert(note.id, card.id.unwrap());
+        jobs.update(job, |j| j.processed += 1);
+    }
+
+    for (name, data) in package.media {
+        Media::new(user_id, deck_id, name, data)
+            .save_in(&mut *tx).await.map_err(database_error)?;
+        jobs.update(job, |j| j.processed += 1);
+    }
+
+    if with_history {
+        let mut reviews: HashMap<i64, Vec<&AnkiReview>> = HashMap::new();
+        for review in &package.reviews {
+            reviews.entry(review.note_id).or_default().push(review);
+        }
+
+        for (note_id, reviews) in reviews {
+            if let Some(card_id) = cards.get(&note_id) {
+                history_from_anki(user_id, *card_id, &reviews)
+                    .save_in(&mut *tx).await.map_err(database_error)?;
+                // Logged as well so statistics include them. Only the previous interval is known,
+                // so undoing one of them treats the card as if it had been new.
+                for review in &reviews {
+                    if let Ok(grade) = Grade::try_from(review.ease) {
+                        let mut log = Review::record(user_id, *card_id, grade, review.time.max(0), None, None, review.ts);
+                        // Learning steps are given in seconds and don't count as an interval
+                        log.prev_interval = Some(review.last_interval.max(0));
+                        log.save_in(&mut *tx).await.map_err(database_error)?;
+                    }
+                }
+            }
+            jobs.update(job, |j| j.processed += reviews.len());
+        }
+    }
+
+    tx.commit().await.map_err(database_error)?;
+    Ok(deck_id)
+}
+
+/// Imports an uploaded Anki package into a new deck in the background.
+/// Poll `GET /api/import/<id>` for progress; the deck id is reported once it finishes.
+#[post("/apkg?<name>&<history>", data = "<file>")]
+pub async fn import_apkg(user: VerifiedUser, db: &State<Db>, jobs: &State<ImportJobs>, name: Option<String>, history: Option<bool>, mut file: TempFile<'_>) -> Result<Custom<Json<ImportJob>>, Custom<String>> {
+    let scratch = tempfile::tempdir().map_err(|_| Custom(
+        Status::InternalServerError,
+        "Could not store upload. Please try again.".to_string(),
+    ))?;
+    let path = scratch.path().join("upload.apkg");
+
+    if file.persist_to(&path).await.is_err() {
+        return Err(Custom(
+            Status::InternalServerError,
+            "Could not store upload. Please try again.".to_string(),
+        ));
+    }
+
+    let job = jobs.create(user.id());
+    let (id, user_id) = (job.id, user.id());
+    let jobs = jobs.inner().clone();
+    let pool = db.0.clone();
+
+    rocket::tokio::spawn(async move {
+        let package = rocket::tokio::task::spawn_blocking(move || {
+            let package = AnkiPackage::read(&path, scratch.path());
+            drop(scratch);
+            package
+        }).await.unwrap_or_else(|_| Err("Could not read Anki package.".to_string()));
+
+        let result = match package {
+            Err(e) => Err(e),
+            Ok(package) => {
+                jobs.update(id, |j| {
+                    j.status = ImportStatus::Running;
+                    j.total = package.notes.len() + package.media.len()
+                        + if history.unwrap_or(false) { package.reviews.len() } else { 0 };
+                });
+                let name = name.or(package.name.clone()).unwrap_or_else(|| "Imported deck".to_string());
+                import(pool, &jobs, id, user_id, package, name, history.unwrap_or(false)).await
+            }
+        };
+        jobs.finish(id, result);
+    });
+
+    Ok(Custom(Status::Accepted, Json(job)))
+}
+
+#[get("/<id>")]
+pub async fn import_progress(user: AuthenticatedUser, jobs: &State<ImportJobs>, id: u64) -> Option<Json<ImportJob>> {
+    jobs.get(id, user.id()).map(Json)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub enum DelimitedFormat {
+    Csv,
+    Tsv,
+}
+
+/// A column given either by its header name or by its zero-based position
+enum Column {
+    Index(usize),
+    Name(String),
+}
+
+impl Column {
+    fn parse(column: &str) -> Column {
+        match column.parse() {
+            Ok(index) => Column::Index(index),
+            Err(_) => Column::Name(column.to_string()),
+        }
+    }
+
+    fn resolve(&self, headers: Option<&csv::StringRecord>) -> Result<usize, String> {
+        match (self, headers) {
+            (Column::Index(index), _) => Ok(*index),
+            (Column::Name(name), Some(headers)) => headers.iter()
+                .position(|h| h.trim().eq_ignore_ascii_case(name))
+                .ok_or_else(|| format!("Column \"{}\" does not exist.", name)),
+            (Column::Name(name), None) => Err(format!("Column \"{}\" can only be used with headers.", name)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RowIssue {
+    /// Line of the row in the uploaded file, starting at 1
+    pub line: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CsvImportReport {
+    pub dry_run: bool,
+    pub rows: usize,
+    pub imported: usize,
+    pub errors: Vec<RowIssue>,
+    /// Rows whose front already exists in the deck or earlier in the file; these are skipped
+    pub duplicates: Vec<RowIssue>,
+}
+
+/// Imports the rows of a CSV or TSV upload as cards of an existing deck.
+/// With `dry_run` only the validation report is returned. Otherwise nothing is imported
+/// unless every row is valid, and all cards are inserted in a single transaction.
+#[post("/<id>/import?<format>&<front>&<back>&<tags>&<headers>&<dry_run>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn import_delimited(db: Connection<Db>, user: VerifiedUser, limits: &Limits, id: i32, format: Option<DelimitedFormat>, front: Option<String>, back: Option<String>, tags: Option<String>, headers: Option<bool>, dry_run: Option<bool>, data: Data<'_>) -> Result<Custom<Json<CsvImportReport>>, Custom<String>> {
+    let error = |_| Custom(
+        Status::InternalServerError,
+        "Could not import cards. Please try again.".to_string(),
+    );
+
+    let (deck, mut db) = Deck::read(id, db).await;
+    match deck {
+        Some(deck) if deck.user_id == user.id() => {}
+        _ => return Err(Custom(Status::NotFound, "Deck does not exist.".to_string())),
+    }
+
+    let upload = data.open(limits.get("file").unwrap_or_else(|| 1.mebibytes()))
+        .into_string()
+        .await
+        .map_err(|_| Custom(Status::BadRequest, "Could not read upload.".to_string()))?;
+    if !upload.is_complete() {
+        return Err(Custom(Status::PayloadTooLarge, "Upload is too large.".to_string()));
+    }
+
+    let headers = headers.unwrap_or(false);
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(if format == Some(DelimitedFormat::Tsv) { b'\t' } else { b',' })
+        .has_headers(headers)
+        .flexible(true)
+        .from_reader(upload.as_bytes());
+
+    let header_row = if headers {
+        Some(reader.headers().map_err(|_| Custom(Status::BadRequest, "Could not read headers.".to_string()))?.clone())
+    } else {
+        None
+    };
+    let resolve = |column: Option<String>, default: Option<usize>| -> Result<Option<usize>, Custom<String>> {
+        match column {
+            Some(column) => Column::parse(&column).resolve(header_row.as_ref())
+                .map(Some)
+                .map_err(|e| Custom(Status::UnprocessableEntity, e)),
+            None => Ok(default),
+        }
+    };
+    let front = resolve(front, Some(0))?.unwrap();
+    let back = resolve(back, Some(1))?.unwrap();
+    let tags = resolve(tags, None)?;
+
+    let mut existing = sqlx::query(format!("SELECT front FROM {} WHERE deck_id = $1", Card::table()).as_str())
+        .bind(id)
+        .fetch(&mut *db)
+        .map_ok(|r| r.get::<Vec<u8>, _>(0))
+        .try_collect::<HashSet<_>>()
+        .await
+        .map_err(error)?;
+
+    let mut report = CsvImportReport {
+        dry_run: dry_run.unwrap_or(false),
+        rows: 0,
+        imported: 0,
+        errors: vec![],
+        duplicates: vec![],
+    };
+    let mut cards = vec![];
+
+    for (i, record) in reader.records().enumerate() {
+        report.rows += 1;
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map_or(i as u64 + 1, |p| p.line());
+                report.errors.push(RowIssue { line, message: "Row could not be parsed.".to_string() });
+                continue;
+            }
+        };
+        let line = record.position().map_or(i as u64 + 1, |p| p.line());
+        let field = |index: usize| record.get(index).map(str::trim);
+
+        let (card_front, card_back) = match (field(front), field(back)) {
+            (Some(f), Some(b)) if !f.is_empty() && !b.is_empty() => (f, b),
+            (None, _) | (_, None) => {
+                report.errors.push(RowIssue { line, message: "Row is missing a column.".to_string() });
+                continue;
+            }
+            _ => {
+                report.errors.push(RowIssue { line, message: "Front and back must not be empty.".to_string() });
+                continue;
+            }
+        };
+
+        if !existing.insert(card_front.as_bytes().to_vec()) {
+            report.duplicates.push(RowIssue { line, message: format!("\"{}\" is already in the deck.", card_front) });
+            continue;
+        }
+
+        let card_tags = tags.and_then(field)
+            .map(|t| t.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|t| !t.is_empty())
+                .map(|t| t.to_string())
+                .collect())
+            .unwrap_or_default();
+        cards.push(Card::new(user.id(), id, card_front.as_bytes().to_vec(), card_back.as_bytes().to_vec(), card_tags));
+    }
+
+    if report.dry_run {
+        return Ok(Custom(Status::Ok, Json(report)));
+    }
+    if !report.errors.is_empty() {
+        return Ok(Custom(Status::UnprocessableEntity, Json(report)));
+    }
+
+    let mut tx = (&mut *db).begin().await.map_err(error)?;
+    for card in &cards {
+        card.save_in(&mut *tx).await.map_err(error)?;
+    }
+    tx.commit().await.map_err(error)?;
+
+    report.imported = cards.len();
+    Ok(Custom(Status::Created, Json(report)))
+}
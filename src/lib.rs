@@ -3,11 +3,54 @@ use quote::{quote, format_ident};
 use syn::*;
 use parse::Parser;
 use indexmap::IndexMap;
+use std::collections::HashMap;
+
+/// Postgres column type of a model field, and whether it may be NULL
+fn sql_type(ty: &str) -> Option<(&'static str, bool)> {
+    if let Some(inner) = ty.strip_prefix("Option<").and_then(|t| t.strip_suffix('>')) {
+        return sql_type(inner).map(|(t, _)| (t, true));
+    }
+    let t = match ty {
+        "i16" => "SMALLINT",
+        "i32" => "INTEGER",
+        "i64" => "BIGINT",
+        "f32" => "REAL",
+        "f64" => "DOUBLE PRECISION",
+        "bool" => "BOOLEAN",
+        "String" => "TEXT",
+        "Vec<u8>" => "BYTEA",
+        "Vec<String>" => "TEXT[]",
+        "DateTime<Utc>" => "TIMESTAMPTZ",
+        "NaiveDateTime" => "TIMESTAMP",
+        "NaiveDate" => "DATE",
+        _ => return None,
+    };
+    Some((t, false))
+}
+
+/// Type named by a `#[foreign(type = "...")]` attribute
+fn foreign_type(field: &Field) -> Option<Path> {
+    let attr = field.attrs.iter().find(|a| a.path.is_ident("foreign"))?;
+    if let Meta::List(ml) = attr.parse_meta().ok()? {
+        for m in ml.nested {
+            if let NestedMeta::Meta(Meta::NameValue(nv)) = m {
+                if nv.path.is_ident("type") {
+                    if let Lit::Str(s) = &nv.lit {
+                        return parse_str(&s.value()).ok();
+                    }
+                }
+            }
+        }
+    }
+    None
+}
 
 #[proc_macro_attribute]
 pub fn model(args: TokenStream, input: TokenStream) -> TokenStream {
     let args = parse_macro_input!(args as AttributeArgs);
     let mut ast = parse_macro_input!(input as DeriveInput);
+    // #[model(versioned)] adds a version column for optimistic concurrency control
+    let versioned = args.iter().any(|arg| matches!(arg, NestedMeta::Meta(Meta::Path(path)) if path.is_ident("versioned")));
     if let Data::Struct(ref mut struct_data) = &mut ast.data {
         if let Fields::Named(fields) = &mut struct_data.fields {
             let named = &mut fields.named;
@@ -34,6 +77,12 @@ pub fn model(args: TokenStream, input: TokenStream) -> TokenStream {
                 ty: Type::Verbatim(quote! { Option<i32> })
             };
             named.insert(0, id_field);
+            if versioned {
+                named.push(Field::parse_named.parse2(quote! {
+                    #[serde(default)]
+                    pub version: i32
+                }).unwrap());
+            }
         }
     } else {
         return quote! {
@@ -60,6 +109,31 @@ pub fn model(args: TokenStream, input: TokenStream) -> TokenStream {
     if let Data::Struct(s) = &ast.data {
         if let Fields::Named(f) = &s.fields {
             let fields = &f.named;
+            // Column definitions for CREATE TABLE; foreign keys name the referenced table at runtime
+            let mut column_defs = vec![quote! { "id SERIAL PRIMARY KEY".to_string() }];
+            for field in fields.iter().skip(1) {
+                let column = field.ident.as_ref().unwrap().to_string();
+                let ty = &field.ty;
+                let ty = quote! { #ty }.to_string().replace(' ', "");
+                let (sql_type, nullable) = match sql_type(&ty) {
+                    Some(t) => t,
+                    None => {
+                        let message = format!("unsupported column type {} of {}", ty, column);
+                        return quote! { compile_error!(#message); }.into();
+                    }
+                };
+                let mut def = format!("{} {}{}", column, sql_type, if nullable { "" } else { " NOT NULL" });
+                if versioned && column == "version" {
+                    def.push_str(" DEFAULT 1");
+                }
+                column_defs.push(match foreign_type(field) {
+                    Some(path) => quote! { format!("{} REFERENCES {}(id) ON DELETE CASCADE", #def, <#path>::table()) },
+                    None => quote! { #def.to_string() },
+                });
+            }
+            let create_sql = format!("CREATE TABLE {} (\n    {{}}\n)", table);
+            let drop_sql = format!("DROP TABLE {}", table);
+
             let types: Vec<_> = fields.iter().map(|x| &x.ty).collect();
             let fields: Vec<_> = fields.iter().map(|x| &x.ident).collect();
             // row.get(field) repetitions for ::from(PgRow)
@@ -71,17 +145,22 @@ pub fn model(args: TokenStream, input: TokenStream) -> TokenStream {
                     #field: r.get(#field_str),
                 };
             }
+            // The version column is maintained by the database, so it is never bound from the struct
+            let is_version = |f: &&&Option<Ident>| versioned && quote! { #f }.to_string() == "version";
             // SQL variables ($1, $2, etc) in INSERT statement
-            let size = fields.len();
             let col_vars = fields.iter().skip(1).map(|f| quote! { #f }.to_string()).collect::<Vec<String>>().join(",");
             let mut val_vars = vec![];
-            for i in 1..size {
-                val_vars.push(format!("${}", i));
+            for field in fields.iter().skip(1) {
+                if is_version(&field) {
+                    val_vars.push("1".to_string());
+                } else {
+                    val_vars.push(format!("${}", val_vars.len() + 1));
+                }
             }
             // Struct fields to bind as variables in INSERT statement
             let val_vars = val_vars.join(",");
             let mut bind_values = quote! {};
-            for field in fields.iter().skip(1) {
+            for field in fields.iter().skip(1).filter(|f| !is_version(f)) {
                 bind_values = quote! {
                     #bind_values.bind(&self.#field)
                 };
@@ -89,23 +168,94 @@ pub fn model(args: TokenStream, input: TokenStream) -> TokenStream {
             let insert_sql = format!("INSERT INTO {} ({}) VALUES ({}) RETURNING *", table, col_vars, val_vars);
             // SQL variables ($1, $2, etc) in UPDATE statement
             let mut set_vars = vec![];
-            for (i, field) in fields.iter().skip(1).enumerate() {
+            for (i, field) in fields.iter().skip(1).filter(|f| !is_version(f)).enumerate() {
                 set_vars.push(format!("{} = ${}", quote! { #field }.to_string(), i + 1));
             }
-            let set_vars = set_vars.join(",");
+            let size = set_vars.len() + 1;
             // Struct fields to bind as variables in UPDATE statement
             let mut set_binds = quote! {};
-            for field in fields.iter().skip(1) {
+            for field in fields.iter().skip(1).filter(|f| !is_version(f)) {
                 set_binds = quote! {
                     #set_binds.bind(&self.#field)
                 };
             }
-            let update_sql = format!("UPDATE {} SET {} RETURNING *", table, set_vars);
+            // Versioned rows are only updated if nobody else saved them in the meantime
+            let (update_sql, version_bind) = if versioned {
+                set_vars.push("version = version + 1".to_string());
+                (format!("UPDATE {} SET {} WHERE id = ${} AND version = ${} RETURNING *", table, set_vars.join(","), size, size + 1),
+                 quote! { .bind(self.version) })
+            } else {
+                (format!("UPDATE {} SET {} WHERE id = ${} RETURNING *", table, set_vars.join(","), size), quote! {})
+            };
+
+            // Columns that list routes may filter and sort by (binary data and arrays are left out)
+            let columns: Vec<String> = std::iter::zip(&fields, &types)
+                .filter(|(_, ty)| !quote! { #ty }.to_string().starts_with("Vec"))
+                .map(|(field, _)| quote! { #field }.to_string())
+                .collect();
+            let count_sql = format!("SELECT COUNT(*) FROM {} WHERE ", table);
+            let list_sql = format!("SELECT * FROM {} WHERE ", table);
+
+            // Every field except id becomes optional in the generated <Name>Patch
+            let patch_name = format_ident!("{}Patch", name);
+            let vis = &ast.vis;
+            let mut patch_fields = quote! {};
+            let mut patch_sets = quote! {};
+            let mut patch_binds = quote! {};
+            for (field, ty) in std::iter::zip(&fields, &types).skip(1) {
+                let field_str = quote! { #field }.to_string();
+                patch_fields = quote! {
+                    #patch_fields
+                    #[serde(default, deserialize_with = "crate::models::present")]
+                    pub #field: Option<#ty>,
+                };
+                // A patched version is the one the client expects to overwrite
+                if is_version(&field) {
+                    continue;
+                }
+                patch_sets = quote! {
+                    #patch_sets
+                    if patch.#field.is_some() {
+                        columns.push(#field_str);
+                    }
+                };
+                patch_binds = quote! {
+                    #patch_binds
+                    if let Some(value) = &patch.#field {
+                        query = query.bind(value);
+                    }
+                };
+            }
+
+            let (patch_version, patch_version_bind) = if versioned {
+                (quote! {
+                    let mut sets = sets;
+                    sets.push("version = version + 1".to_string());
+                    let condition = match patch.version {
+                        Some(_) => format!("id = ${} AND version = ${}", columns.len() + 1, columns.len() + 2),
+                        None => format!("id = ${}", columns.len() + 1),
+                    };
+                }, quote! {
+                    if let Some(version) = patch.version {
+                        query = query.bind(version);
+                    }
+                })
+            } else {
+                (quote! {
+                    let condition = format!("id = ${}", columns.len() + 1);
+                }, quote! {})
+            };
 
             // Fields and types to accept in ::new() (skipping the first field/type, id)
             let mut new_params = quote! {};
             let mut new_constructor = quote! {};
             for (field, ty) in std::iter::zip(fields, types).skip(1) {
+                if versioned && quote! { #field }.to_string() == "version" {
+                    new_constructor = quote! {
+                        #new_constructor #field: 1,
+                    };
+                    continue;
+                }
                 new_params = quote! {
                     #new_params #field: #ty,
                 };
@@ -123,6 +273,13 @@ pub fn model(args: TokenStream, input: TokenStream) -> TokenStream {
                 #[serde(crate = "rocket::serde")]
                 #ast
 
+                /// Changed fields of a partial update; absent fields are left alone
+                #[derive(Debug, Default, Deserialize)]
+                #[serde(crate = "rocket::serde", deny_unknown_fields)]
+                #vis struct #patch_name {
+                    #patch_fields
+                }
+
                 impl From<rocket_db_pools::sqlx::postgres::PgRow> for #name {
                     fn from(r: rocket_db_pools::sqlx::postgres::PgRow) -> Self {
                         use rocket_db_pools::sqlx::Row;
@@ -165,9 +322,11 @@ pub fn model(args: TokenStream, input: TokenStream) -> TokenStream {
                                     .map_ok(|r| <#name>::from(r))
                                     .await.ok(), db
                             ),
-                            Some(_) => (
+                            Some(id) => (
                                 rocket_db_pools::sqlx::query(#update_sql)
                                     #set_binds
+                                    .bind(id)
+                                    #version_bind
                                     .fetch_one(&mut *db)
                                     .map_ok(|r| <#name>::from(r))
                                     .await.ok(), db
@@ -175,6 +334,46 @@ pub fn model(args: TokenStream, input: TokenStream) -> TokenStream {
                         }
                     }
 
+                    /// Same as `save`, but runs on a borrowed connection such as an open transaction
+                    pub async fn save_in(&self, conn: &mut rocket_db_pools::sqlx::PgConnection) -> std::result::Result<Self, rocket_db_pools::sqlx::Error> {
+                        match self.id {
+                            None => rocket_db_pools::sqlx::query(#insert_sql)
+                                #bind_values
+                                .fetch_one(conn)
+                                .await
+                                .map(|r| <#name>::from(r)),
+                            Some(id) => rocket_db_pools::sqlx::query(#update_sql)
+                                #set_binds
+                                .bind(id)
+                                #version_bind
+                                .fetch_one(conn)
+                                .await
+                                .map(|r| <#name>::from(r))
+                        }
+                    }
+
+                    /// Updates only the columns present in `patch`
+                    pub async fn patch(id: i32, patch: &#patch_name, mut db: rocket_db_pools::Connection<crate::Db>) -> (Option<Self>, rocket_db_pools::Connection<crate::Db>) {
+                        use rocket::futures::TryFutureExt;
+                        let mut columns: Vec<&str> = vec![];
+                        #patch_sets
+                        if columns.is_empty() {
+                            return Self::read(id, db).await;
+                        }
+
+                        let sets: Vec<String> = columns.iter().enumerate().map(|(i, c)| format!("{} = ${}", c, i + 1)).collect();
+                        #patch_version
+                        let sql = format!("UPDATE {} SET {} WHERE {} RETURNING *", <#name>::table(), sets.join(","), condition);
+                        let mut query = rocket_db_pools::sqlx::query(sql.as_str());
+                        #patch_binds
+                        query = query.bind(id);
+                        #patch_version_bind
+                        (query
+                            .fetch_one(&mut *db)
+                            .map_ok(|r| <#name>::from(r))
+                            .await.ok(), db)
+                    }
+
                     pub async fn read(id: i32, mut db: rocket_db_pools::Connection<crate::Db>) -> (Option<Self>, rocket_db_pools::Connection<crate::Db>) {
                         use rocket::futures::TryFutureExt;
                         (rocket_db_pools::sqlx::query(#read_sql)
@@ -193,6 +392,56 @@ pub fn model(args: TokenStream, input: TokenStream) -> TokenStream {
                             .await, db)
                     }
 
+                    /// DDL for this model's table, with foreign keys to the tables of `#[foreign]` fields
+                    pub fn create_table_sql() -> String {
+                        let columns: Vec<String> = vec![#(#column_defs),*];
+                        format!(#create_sql, columns.join(",\n    "))
+                    }
+
+                    pub fn drop_table_sql() -> String {
+                        #drop_sql.to_string()
+                    }
+
+                    pub fn columns() -> &'static [&'static str] {
+                        &[#(#columns),*]
+                    }
+
+                    /// One page of the rows matching `scope` (an SQL condition that may use `user_id` as $1) and the query's filters
+                    pub async fn list(scope: &str, user_id: Option<i32>, query: &crate::models::ListQuery, mut db: rocket_db_pools::Connection<crate::Db>) -> (std::result::Result<crate::models::Page<Self>, rocket_db_pools::sqlx::Error>, rocket_db_pools::Connection<crate::Db>) {
+                        use rocket::futures::TryStreamExt;
+                        use rocket_db_pools::sqlx::Row;
+                        let condition = query.condition(scope);
+
+                        let count_sql = format!("{}{}", #count_sql, condition);
+                        let mut count = rocket_db_pools::sqlx::query(count_sql.as_str()).bind(user_id);
+                        for value in query.values() {
+                            count = count.bind(value);
+                        }
+                        let total: i64 = match count.fetch_one(&mut *db).await {
+                            Ok(r) => r.get(0),
+                            Err(e) => return (Err(e), db),
+                        };
+
+                        let list_sql = format!("{}{} {}", #list_sql, condition, query.order());
+                        let mut list = rocket_db_pools::sqlx::query(list_sql.as_str()).bind(user_id);
+                        for value in query.values() {
+                            list = list.bind(value);
+                        }
+                        let items = list
+                            .fetch(&mut *db)
+                            .map_ok(|r| <#name>::from(r))
+                            .try_collect::<Vec<_>>()
+                            .await;
+
+                        (items.map(|items| crate::models::Page {
+                            items,
+                            page: query.page,
+                            per_page: query.per_page,
+                            total,
+                            pages: (total + query.per_page - 1) / query.per_page,
+                        }), db)
+                    }
+
                     pub fn json(self) -> rocket::serde::json::Json<#name> {
                         rocket::serde::json::Json(self)
                     }
@@ -332,6 +581,12 @@ pub fn impl_related(input: TokenStream) -> TokenStream {
                 let ident = &field.ident.unwrap();
                 let ident_str = &ident.to_string();
                 if ident_str == "id" { continue; }
+                if ident_str == "version" {
+                    new_constructor = quote! {
+                        #new_constructor #ident: 1,
+                    };
+                    continue;
+                }
                 let ident_no_id = format_ident!("{}", str::replace(ident_str, "_id", ""));
                 let ty = &field.ty;
                 match path {
@@ -386,7 +641,7 @@ pub fn impl_create_as_owner(input: TokenStream) -> TokenStream {
 
     quote! {
         #[post("/", data = "<model>")]
-        pub async fn #fname(db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::AuthenticatedUser, model: rocket::serde::json::Json<#name>) -> Option<rocket::response::status::Created<rocket::serde::json::Json<#name>>> {
+        pub async fn #fname(db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::VerifiedUser, model: rocket::serde::json::Json<#name>) -> Option<rocket::response::status::Created<rocket::serde::json::Json<#name>>> {
             let mut model = model.into_inner();
             model.user_id = user.id();
             let (model, _) = model.save(db).await;
@@ -399,130 +654,332 @@ pub fn impl_create_as_owner(input: TokenStream) -> TokenStream {
     }.into()
 }
 
-#[proc_macro_derive(Read)]
-pub fn impl_read(input: TokenStream) -> TokenStream {
-    let ast = parse_macro_input!(input as DeriveInput);
-    let name = &ast.ident;
-    let fname = format_ident!("read_{}", &name.to_string().to_lowercase());
+/// One step of a relation in a policy, `Model(column)` or `Model(column = field)`: a row of `Model`
+/// whose `column` matches `field` (`id` by default) of the row before it
+fn policy_step(step: &str) -> std::result::Result<(Path, Ident, Ident), String> {
+    let invalid = || format!("invalid policy relation {}", step);
+    let (model, rest) = step.split_once('(').ok_or_else(invalid)?;
+    let inner = rest.strip_suffix(')').ok_or_else(invalid)?;
+    let (column, field) = match inner.split_once('=') {
+        Some((column, field)) => (column.trim(), field.trim()),
+        None => (inner.trim(), "id"),
+    };
 
-    quote! {
-        #[get("/<id>")]
-        pub async fn #fname(db: rocket_db_pools::Connection<crate::Db>, _user: crate::auth::User, id: i32) -> Option<rocket::serde::json::Json<#name>> {
-            let (m, _db) = <#name>::read(id, db).await;
-            m.json()
-        }
-    }.into()
+    Ok((
+        parse_str(model.trim()).map_err(|_| invalid())?,
+        parse_str(column).map_err(|_| invalid())?,
+        parse_str(field).map_err(|_| invalid())?,
+    ))
 }
 
-#[proc_macro_derive(ReadIfVisible)]
-pub fn impl_read_if_visible(input: TokenStream) -> TokenStream {
-    let ast = parse_macro_input!(input as DeriveInput);
-    let name = &ast.ident;
-    let fname = format_ident!("read_{}", &name.to_string().to_lowercase());
+/// Expression building the SQL condition of a policy like `owner | visible | subscriber`.
+/// Table names are only known at runtime, so the SQL is put together there; the model's own
+/// table is referred to by name and the user's id is `$1`.
+fn policy_sql(expression: &str, aliases: &HashMap<String, String>) -> std::result::Result<proc_macro2::TokenStream, String> {
+    let mut terms = vec![];
+    for term in expression.split('|').map(str::trim) {
+        terms.push(match term {
+            "owner" => quote! {{
+                let _ = |m: &Self| { let _ = &m.user_id; };
+                format!("{}.user_id = $1", Self::table())
+            }},
+            // Rows without visibility are public, hidden ones (true) are left to other terms
+            "visible" => quote! {{
+                let _ = |m: &Self| { let _ = &m.visibility; };
+                format!("{0}.visibility IS NULL OR (NOT {0}.visibility AND $1 IS NOT NULL)", Self::table())
+            }},
+            "anyone" => quote! { "TRUE".to_string() },
+            // Aliases are expanded without aliases, so they can't refer to each other
+            alias if aliases.contains_key(alias) => policy_sql(&aliases[alias], &HashMap::new())?,
+            relation => {
+                // A chain of rows leading from this one to a row of the user, e.g.
+                // `CourseDeck(deck_id) > CourseSubscription(course_id = course_id)`
+                let steps = relation.split('>')
+                    .map(|step| policy_step(step.trim()))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                let mut tables = vec![];
+                let mut joins = vec![];
+                let mut table_args = quote! {};
+                let mut checks = quote! {};
+                let mut previous = quote! { Self };
+                for (i, (model, column, field)) in steps.iter().enumerate() {
+                    let table = format_ident!("t{}", i);
+                    tables.push(format!("{{{}}} p{}", table, i));
+                    let on = if i == 0 { "{outer}".to_string() } else { format!("p{}", i - 1) };
+                    joins.push(format!("p{}.{} = {}.{}", i, column, on, field));
+                    table_args = quote! { #table_args #table = <#model>::table(), };
+                    checks = quote! {
+                        #checks
+                        let _ = |m: &#previous| { let _ = &m.#field; };
+                        let _ = |m: &#model| { let _ = &m.#column; };
+                    };
+                    previous = quote! { #model };
+                }
+                joins.push(format!("p{}.user_id = $1", steps.len() - 1));
+                let sql = format!("EXISTS (SELECT 1 FROM {} WHERE {})", tables.join(", "), joins.join(" AND "));
 
-    quote! {
-        #[get("/<id>")]
-        pub async fn #fname(db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::User, id: i32) -> Option<rocket::serde::json::Json<#name>> {
-            let (m, _db) = <#name>::read(id, db).await;
-            if m.is_none() {
-                return None;
+                quote! {{
+                    #checks
+                    let _ = |m: &#previous| { let _ = &m.user_id; };
+                    format!(#sql, #table_args outer = Self::table())
+                }}
             }
-            let m = m.unwrap();
-            if m.visibility.is_some()  {
-                match user {
-                    crate::auth::User::Guest =>  {
-                        return None;
-                    },
-                    crate::auth::User::Authenticated(user) => {
-                        if m.visibility.unwrap() && m.user_id != user.id() {
-                            return None;
-                        }
-                    }
-                }
+        });
+    }
+
+    Ok(quote! {
+        [#(#terms),*].iter().map(|t| format!("({})", t)).collect::<Vec<_>>().join(" OR ")
+    })
+}
+
+/// Routes reading, updating and deleting rows of a model, allowed by a policy for each, e.g.
+/// `#[policy(read = "owner | visible | subscriber", update = "owner", delete = "owner", subscriber = "DeckSubscription(deck_id)")]`.
+/// It goes between `#[model]` and `#[derive]`. Policies combine `owner`, `visible`, `anyone` and
+/// relations to other tables, written directly or named by an alias such as `subscriber` above.
+/// Only the routes of the given actions are generated, and moderators and admins pass every policy.
+#[proc_macro_attribute]
+pub fn policy(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let mut policies = HashMap::new();
+    let mut aliases = HashMap::new();
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue { path, lit: Lit::Str(s), .. })) if path.get_ident().is_some() => {
+                let name = path.get_ident().unwrap().to_string();
+                match name.as_str() {
+                    "read" | "update" | "delete" => policies.insert(name, s.value()),
+                    _ => aliases.insert(name, s.value()),
+                };
             }
-            Some(m.json())
+            _ => return quote! {
+                compile_error!("policy accepts name = \"...\" pairs");
+            }.into(),
         }
-    }.into()
-}
+    }
 
-#[proc_macro_derive(ReadIfOwner)]
-pub fn impl_read_if_owner(input: TokenStream) -> TokenStream {
     let ast = parse_macro_input!(input as DeriveInput);
     let name = &ast.ident;
-    let fname = format_ident!("read_{}", &name.to_string().to_lowercase());
+    let lower_name = name.to_string().to_lowercase();
 
-    quote! {
-        #[get("/<id>")]
-        pub async fn #fname(db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::User, id: i32) -> Option<rocket::serde::json::Json<#name>> {
-            if let crate::auth::User::Guest = user {
-                return None;
-            }
+    let mut scopes = quote! {};
+    for action in ["read", "update", "delete"] {
+        if let Some(expression) = policies.get(action) {
+            let sql = match policy_sql(expression, &aliases) {
+                Ok(sql) => sql,
+                Err(message) => return quote! { compile_error!(#message); }.into(),
+            };
+            let scope = format_ident!("{}_scope", action);
+            let doc = format!(" Rows the user with the id `$1` may {}, as an SQL condition", action);
+            scopes = quote! {
+                #scopes
 
-            let (m, _db) = <#name>::read(id, db).await;
-            if m.is_none() {
-                return None;
+                #[doc = #doc]
+                pub fn #scope(staff: bool) -> String {
+                    if staff {
+                        return "TRUE".to_string();
+                    }
+                    #sql
+                }
+            };
+        }
+    }
+
+    let mut routes = quote! {};
+
+    if policies.contains_key("read") {
+        let fname = format_ident!("read_{}", &lower_name);
+        let lname = format_ident!("list_{}", &lower_name);
+        let error = format!("Could not list {}. Please try again.", &lower_name);
+
+        routes = quote! {
+            #routes
+
+            #[get("/<id>")]
+            pub async fn #fname(db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::User, id: i32) -> Option<rocket::serde::json::Json<#name>> {
+                let (m, _db) = <#name>::find_permitted(id, &<#name>::read_scope(user.is_staff()), user.id(), db).await;
+                m.ok().flatten().map(rocket::serde::json::Json)
             }
-            let m = m.unwrap();
-            if m.user_id != user.id().unwrap() {
-                return None;
+
+            #[get("/?<params..>")]
+            pub async fn #lname(db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::User, params: std::collections::HashMap<String, String>) -> std::result::Result<rocket::serde::json::Json<crate::models::Page<#name>>, rocket::response::status::Custom<String>> {
+                let query = crate::models::ListQuery::parse(params, <#name>::columns())
+                    .map_err(|e| rocket::response::status::Custom(rocket::http::Status::BadRequest, e))?;
+                let scope = <#name>::read_scope(user.is_staff());
+                let (page, _db) = <#name>::list(&scope, user.id(), &query, db).await;
+                page.map(rocket::serde::json::Json).map_err(|_| rocket::response::status::Custom(
+                    rocket::http::Status::InternalServerError,
+                    #error.to_string(),
+                ))
             }
-            Some(m.json())
-        }
-    }.into()
-}
+        };
+    }
 
-#[proc_macro_derive(UpdateIfOwner)]
-pub fn impl_update_if_owner(input: TokenStream) -> TokenStream {
-    let ast = parse_macro_input!(input as DeriveInput);
-    let name = &ast.ident;
-    let fname = format_ident!("update_{}", &name.to_string().to_lowercase());
+    if policies.contains_key("update") {
+        let fname = format_ident!("update_{}", &lower_name);
+        let pname = format_ident!("patch_{}", &lower_name);
+        let patch_name = format_ident!("{}Patch", name);
+        let not_found = format!("{} does not exist.", &name);
+        let error = format!("Could not update {}. Please try again.", &lower_name);
+        let versioned = match &ast.data {
+            Data::Struct(s) => s.fields.iter().any(|f| f.ident.as_ref().is_some_and(|i| i == "version")),
+            _ => false,
+        };
 
-    quote! {
-        #[put("/", data = "<model>")]
-        pub async fn #fname(db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::AuthenticatedUser, model: rocket::serde::json::Json<#name>) -> Option<rocket::serde::json::Json<#name>> {
-            let model = model.into_inner();
-            if model.id.is_none() {
-                return None;
+        // Versioned models are compared with the stored copy, which is sent back on conflict
+        let (version_check, patch_version_check) = if versioned {
+            (quote! {
+                if model.version != current.version {
+                    return Err(crate::models::Rejected::Conflict(current));
+                }
+            }, quote! {
+                if patch.version.map_or(false, |version| version != current.version) {
+                    return Err(crate::models::Rejected::Conflict(current));
+                }
+            })
+        } else {
+            (quote! {}, quote! {})
+        };
+        // A failed save may have lost a race against another update
+        let save_failed = if versioned {
+            quote! {
+                match <#name>::read(id, db).await {
+                    (Some(current), _) => Err(crate::models::Rejected::Conflict(current)),
+                    _ => Err(crate::models::Rejected::Failed(rocket::http::Status::InternalServerError, #error.to_string())),
+                }
             }
+        } else {
+            quote! {
+                Err(crate::models::Rejected::Failed(rocket::http::Status::InternalServerError, #error.to_string()))
+            }
+        };
+        let current = quote! {
+            let (current, db) = <#name>::find_permitted(id, &<#name>::update_scope(user.is_staff()), Some(user.id()), db).await;
+            let current = match current {
+                Ok(Some(current)) => current,
+                Ok(None) => return Err(crate::models::Rejected::Failed(rocket::http::Status::NotFound, #not_found.to_string())),
+                Err(_) => return Err(crate::models::Rejected::Failed(rocket::http::Status::InternalServerError, #error.to_string())),
+            };
+        };
+
+        routes = quote! {
+            #routes
 
-            let (model, db) = <#name>::read(model.id.unwrap(), db).await;
-            if model.is_none() {
-                return None;
+            #[put("/<id>", data = "<model>")]
+            pub async fn #fname(db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::AuthenticatedUser, id: i32, model: rocket::serde::json::Json<#name>) -> std::result::Result<rocket::serde::json::Json<#name>, crate::models::Rejected<#name>> {
+                let mut model = model.into_inner();
+                #current
+                #version_check
+
+                // Ids are never deserialized, so the path decides which row is replaced.
+                // Rows keep their owner, also when others may update them.
+                model.id = Some(id);
+                model.user_id = current.user_id;
+                match model.save(db).await {
+                    (Some(m), _) => Ok(rocket::serde::json::Json(m)),
+                    (None, db) => {
+                        #save_failed
+                    }
+                }
             }
 
-            let model = model.unwrap();
-            if model.user_id != user.id() {
-                return None;
+            #[patch("/<id>", data = "<patch>")]
+            pub async fn #pname(db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::AuthenticatedUser, id: i32, patch: rocket::serde::json::Json<#patch_name>) -> std::result::Result<rocket::serde::json::Json<#name>, crate::models::Rejected<#name>> {
+                #current
+                if patch.user_id.map_or(false, |owner| owner != current.user_id) {
+                    return Err(crate::models::Rejected::Failed(rocket::http::Status::Forbidden, "The owner cannot be changed.".to_string()));
+                }
+                #patch_version_check
+
+                match <#name>::patch(id, &patch, db).await {
+                    (Some(m), _) => Ok(rocket::serde::json::Json(m)),
+                    (None, db) => {
+                        #save_failed
+                    }
+                }
             }
+        };
+    }
 
-            let (m, _) = model.save(db).await;
-            m.json()
-        }
-    }.into()
-}
+    if policies.contains_key("delete") {
+        let fname = format_ident!("delete_{}", &lower_name);
 
-#[proc_macro_derive(DeleteIfOwner)]
-pub fn impl_delete_if_owner(input: TokenStream) -> TokenStream {
-    let ast = parse_macro_input!(input as DeriveInput);
-    let name = &ast.ident;
-    let fname = format_ident!("delete_{}", &name.to_string().to_lowercase());
+        routes = quote! {
+            #routes
 
-    quote! {
-        #[delete("/<id>")]
-        pub async fn #fname(db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::AuthenticatedUser, id: i32) -> rocket::serde::json::Json<bool> {
-            let (m, db) = <#name>::find(id, db).await;
-            if m.is_none() {
-                return rocket::serde::json::Json(false);
+            #[delete("/<id>")]
+            pub async fn #fname(mut db: rocket_db_pools::Connection<crate::Db>, user: crate::auth::AuthenticatedUser, id: i32) -> rocket::serde::json::Json<bool> {
+                let sql = format!("DELETE FROM {} WHERE id = $2 AND ({})", <#name>::table(), <#name>::delete_scope(user.is_staff()));
+                let deleted = rocket_db_pools::sqlx::query(sql.as_str())
+                    .bind(user.id())
+                    .bind(id)
+                    .execute(&mut *db)
+                    .await;
+                rocket::serde::json::Json(deleted.map_or(false, |r| r.rows_affected() == 1))
             }
-            if m.unwrap().user_id != user.id() {
-                return rocket::serde::json::Json(false);
+        };
+    }
+
+    quote! {
+        #ast
+
+        impl #name {
+            #scopes
+
+            /// The row with this id if `scope`, one of the conditions above, holds for it
+            pub async fn find_permitted(id: i32, scope: &str, user_id: Option<i32>, mut db: rocket_db_pools::Connection<crate::Db>) -> (std::result::Result<Option<Self>, rocket_db_pools::sqlx::Error>, rocket_db_pools::Connection<crate::Db>) {
+                let sql = format!("SELECT * FROM {} WHERE id = $2 AND ({})", Self::table(), scope);
+                let row = rocket_db_pools::sqlx::query(sql.as_str())
+                    .bind(user_id)
+                    .bind(id)
+                    .fetch_optional(&mut *db)
+                    .await;
+                (row.map(|r| r.map(Self::from)), db)
             }
-            let (rows_affected, _) = <#name>::delete(user.id(), db).await;
-            rocket::serde::json::Json(rows_affected.is_ok())
         }
+
+        #routes
     }.into()
 }
 
+/// Restricts a route to users with one of the given roles, or a role that includes it,
+/// e.g. `#[requires_role(moderator)]`. It goes above the route attribute and adds a guard
+/// that answers `403 Forbidden` to everyone else.
+#[proc_macro_attribute]
+pub fn requires_role(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as AttributeArgs);
+    let mut roles = vec![];
+    for arg in args {
+        let role = match arg {
+            NestedMeta::Meta(Meta::Path(path)) => path.get_ident().map(|i| i.to_string()),
+            NestedMeta::Lit(Lit::Str(s)) => Some(s.value()),
+            _ => None,
+        };
+        match role.as_deref() {
+            Some("user") => roles.push((0, quote! { User })),
+            Some("moderator") => roles.push((1, quote! { Moderator })),
+            Some("admin") => roles.push((2, quote! { Admin })),
+            _ => return quote! {
+                compile_error!("requires_role accepts the roles user, moderator and admin");
+            }.into(),
+        }
+    }
+
+    // Roles include the ones below them, so the lowest one listed is enough
+    let minimum = match roles.into_iter().min_by_key(|(rank, _)| *rank) {
+        Some((_, role)) => role,
+        None => return quote! {
+            compile_error!("requires_role needs at least one role");
+        }.into(),
+    };
+
+    let mut function = parse_macro_input!(input as ItemFn);
+    function.sig.inputs.push(parse_quote! {
+        _role: crate::auth::RoleGuard<{ crate::auth::Role::#minimum as u8 }>
+    });
+
+    quote! { #function }.into()
+}
+
 #[proc_macro_attribute]
 pub fn router(args: TokenStream, input: TokenStream) -> TokenStream {
     let args = parse_macro_input!(args as AttributeArgs);
@@ -555,7 +1012,7 @@ pub fn router(args: TokenStream, input: TokenStream) -> TokenStream {
         let lower_name = &ident.to_string().to_lowercase();
         let mount_point = format!("/{}", &lower_name);
 
-        let methods: Vec<Path> = vec!["create_$", "read_$", "update_$", "delete_$"]
+        let methods: Vec<Path> = ["create_$", "read_$", "list_$", "update_$", "patch_$", "delete_$"]
             .iter().map(|s| {
                 let i = format_ident!("{}", s.replace("$", &lower_name));
                 let mut p_clone = path.clone();
@@ -2,21 +2,30 @@
 extern crate rocket;
 extern crate rocket_cors;
 
+mod db;
 mod models;
 mod auth;
 mod user;
+mod scheduler;
+mod study;
+mod stats;
+mod anki;
+mod import;
+mod export;
+mod migrations;
+mod session;
+mod mail;
+mod verification;
+mod two_factor;
+mod oidc;
+mod api_key;
 
 use rocket::fs::{FileServer, NamedFile};
 use rocket::http::Method;
 use rocket_cors::{AllowedHeaders, AllowedOrigins, Cors, CorsOptions};
-use rocket_db_pools::{sqlx, Database};
+use rocket_db_pools::Database;
 use memra::router;
-
-type Result<T, E = rocket::response::Debug<sqlx::Error>> = std::result::Result<T, E>;
-
-#[derive(Database)]
-#[database("main")]
-pub struct Db(sqlx::PgPool);
+use db::{Db, Result};
 
 #[router(models, Course, Deck, Card, History, Settings, Notification, Addon)]
 pub struct MemraRouter;
@@ -53,9 +62,21 @@ async fn index() -> Option<NamedFile> {
 fn rocket() -> _ {
     rocket::build()
         .attach(Db::init())
+        .attach(migrations::fairing())
+        .attach(auth::AuthConfig::fairing())
+        .attach(mail::Mailer::fairing())
+        .attach(oidc::Oidc::fairing())
         .attach(make_cors())
         .attach(MemraRouter)
+        .manage(import::ImportJobs::default())
+        .manage(verification::ResendThrottle::default())
+        .manage(two_factor::PendingAttempts::default())
         .mount("/public", FileServer::from("app/build"))
-        .mount("/api/users", routes![user::read_user, user::delete_user, user::login, user::register, user::change_password])
+        .mount("/api/users", routes![user::read_user, user::delete_user, user::set_role, user::login, user::register, user::change_password, session::refresh, session::logout, session::logout_all, session::list_sessions, session::delete_session, verification::verify, verification::resend, user::forgot_password, user::reset_password, two_factor::enroll, two_factor::confirm, two_factor::disable, two_factor::login, oidc::login, oidc::callback, api_key::create_api_key, api_key::list_api_keys, api_key::revoke_api_key])
+        .mount("/api/study", routes![study::queue, study::review, study::undo, study::start_session, study::finish_session])
+        .mount("/api/study/sessions", routes![models::read_studysession, models::list_studysession])
+        .mount("/api/users/stats", routes![stats::summary, stats::heatmap, stats::current_streak, stats::true_retention, stats::forecast])
+        .mount("/api/import", routes![import::import_apkg, import::import_progress])
+        .mount("/api/deck", routes![export::export_deck, import::import_delimited])
         .mount("/", routes![index])
 }
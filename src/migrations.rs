@@ -0,0 +1,27 @@
+use rocket::fairing::{self, AdHoc};
+use rocket::{Build, Rocket};
+use rocket_db_pools::Database;
+use super::Db;
+
+/// Migrations from `migrations/`, embedded at compile time
+pub static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("./migrations");
+
+async fn run(rocket: Rocket<Build>) -> fairing::Result {
+    let db = match Db::fetch(&rocket) {
+        Some(db) => db,
+        None => return Err(rocket),
+    };
+
+    match MIGRATOR.run(&**db).await {
+        Ok(()) => Ok(rocket),
+        Err(e) => {
+            error!("Failed to run database migrations: {}", e);
+            Err(rocket)
+        }
+    }
+}
+
+/// Applies pending migrations once the database pool is up, so it must be attached after `Db::init()`
+pub fn fairing() -> AdHoc {
+    AdHoc::try_on_ignite("Database migrations", run)
+}
@@ -1,6 +1,16 @@
-use rocket::serde::{Deserialize, Serialize};
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, status::Custom};
+use rocket::serde::{Deserialize, Deserializer, Serialize, json::Json};
 use chrono::{Utc, DateTime};
 use memra::*;
+use std::collections::HashMap;
+
+/// Page size of the generated list routes when none is requested
+const DEFAULT_PER_PAGE: i64 = 20;
+const MAXIMUM_PER_PAGE: i64 = 100;
+/// Highest page number of the generated list routes, which keeps offsets from overflowing
+const MAXIMUM_PAGE: i64 = 1_000_000;
 
 #[model]
 pub struct User {
@@ -11,10 +21,15 @@ pub struct User {
     pub verified: Option<bool>,
     pub created_at: DateTime<Utc>,
     pub last_login: DateTime<Utc>,
+    #[serde(default)]
+    pub disabled: bool,
+    /// `user`, `moderator` or `admin`, see `auth::Role`
+    #[serde(default = "default_role")]
+    pub role: String,
 }
 
 #[model(table = "credentials")]
-#[derive(Related, UpdateIfOwner)]
+#[derive(Related)]
 pub struct Credentials {
     #[foreign(type = "User")]
     pub user_id: i32,
@@ -22,7 +37,13 @@ pub struct Credentials {
 }
 
 #[model]
-#[derive(Related, CreateAsOwner, ReadIfVisible, UpdateIfOwner, DeleteIfOwner)]
+#[policy(
+    read = "owner | visible | subscriber",
+    update = "owner",
+    delete = "owner",
+    subscriber = "CourseSubscription(course_id)",
+)]
+#[derive(Related, CreateAsOwner)]
 pub struct Course {
     #[foreign(type = "User")]
     pub user_id: i32,
@@ -32,7 +53,13 @@ pub struct Course {
 }
 
 #[model]
-#[derive(Related, CreateAsOwner, ReadIfVisible, UpdateIfOwner, DeleteIfOwner)]
+#[policy(
+    read = "owner | visible | subscriber",
+    update = "owner",
+    delete = "owner",
+    subscriber = "DeckSubscription(deck_id) | CourseDeck(deck_id) > CourseSubscription(course_id = course_id)",
+)]
+#[derive(Related, CreateAsOwner)]
 pub struct Deck {
     #[foreign(type = "User")]
     pub user_id: i32,
@@ -41,8 +68,14 @@ pub struct Deck {
     pub image: Vec<u8>,
 }
 
-#[model]
-#[derive(Related, CreateAsOwner, ReadIfOwner, UpdateIfOwner, DeleteIfOwner)]
+#[model(versioned)]
+#[policy(
+    read = "owner | subscriber",
+    update = "owner",
+    delete = "owner",
+    subscriber = "DeckSubscription(deck_id = deck_id) | CourseDeck(deck_id = deck_id) > CourseSubscription(course_id = course_id)",
+)]
+#[derive(Related, CreateAsOwner)]
 pub struct Card {
     #[foreign(type = "User")]
     pub user_id: i32,
@@ -50,10 +83,24 @@ pub struct Card {
     pub deck_id: i32,
     pub front: Vec<u8>,
     pub back: Vec<u8>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[model(table = "media")]
+#[derive(Related)]
+pub struct Media {
+    #[foreign(type = "User")]
+    pub user_id: i32,
+    #[foreign(type = "Deck")]
+    pub deck_id: i32,
+    pub name: String,
+    pub data: Vec<u8>,
 }
 
 #[model(table = "history")]
-#[derive(Related, CreateAsOwner, ReadIfOwner, UpdateIfOwner, DeleteIfOwner)]
+#[policy(read = "owner", update = "owner", delete = "owner")]
+#[derive(Related, CreateAsOwner)]
 pub struct History {
     #[foreign(type = "User")]
     pub user_id: i32,
@@ -63,18 +110,70 @@ pub struct History {
     pub num_confident: i32,
     pub num_correct: i32,
     pub num_wrong: i32,
+    pub ease: f64,
+    pub interval: i32,
+    pub repetitions: i32,
+    pub lapses: i32,
+    pub stability: f64,
+    pub difficulty: f64,
+    pub due: DateTime<Utc>,
+    pub time_spent: i64,
+}
+
+/// Append-only log of every answer, holding the card's scheduling state from before it
+#[model]
+#[derive(Related)]
+pub struct Review {
+    #[foreign(type = "User")]
+    pub user_id: i32,
+    #[foreign(type = "Card")]
+    pub card_id: i32,
+    pub ts: DateTime<Utc>,
+    pub grade: i32,
+    pub response_time: i32,
+    pub undone: bool,
+    pub session_id: Option<i32>,
+    pub prev_ts: Option<DateTime<Utc>>,
+    pub prev_ease: Option<f64>,
+    pub prev_interval: Option<i32>,
+    pub prev_repetitions: Option<i32>,
+    pub prev_lapses: Option<i32>,
+    pub prev_stability: Option<f64>,
+    pub prev_difficulty: Option<f64>,
+    pub prev_due: Option<DateTime<Utc>>,
+}
+
+#[model(table = "study_sessions")]
+#[policy(read = "owner")]
+#[derive(Related)]
+pub struct StudySession {
+    #[foreign(type = "User")]
+    pub user_id: i32,
+    pub deck_id: Option<i32>,
+    pub course_id: Option<i32>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub cards_seen: i32,
+    pub num_reviews: i32,
+    pub num_correct: i32,
+    pub time_spent: i64,
 }
 
 #[model(table = "settings")]
-#[derive(Related, CreateAsOwner, ReadIfOwner, UpdateIfOwner, DeleteIfOwner)]
+#[policy(read = "owner", update = "owner", delete = "owner")]
+#[derive(Related, CreateAsOwner)]
 pub struct Settings {
     #[foreign(type = "User")]
     pub user_id: i32,
     pub avatar: Vec<u8>,
+    pub algorithm: Option<String>,
+    pub new_cards_per_day: Option<i32>,
+    pub reviews_per_day: Option<i32>,
 }
 
 #[model]
-#[derive(Related, CreateAsOwner, ReadIfOwner, UpdateIfOwner, DeleteIfOwner)]
+#[policy(read = "owner", update = "owner", delete = "owner")]
+#[derive(Related, CreateAsOwner)]
 pub struct Notification {
     #[foreign(type = "User")]
     pub user_id: i32,
@@ -84,7 +183,8 @@ pub struct Notification {
 }
 
 #[model]
-#[derive(Related, CreateAsOwner, ReadIfVisible, UpdateIfOwner, DeleteIfOwner)]
+#[policy(read = "owner | visible", update = "owner", delete = "owner")]
+#[derive(Related, CreateAsOwner)]
 pub struct Addon {
     #[foreign(type = "User")]
     pub user_id: i32,
@@ -126,19 +226,216 @@ pub struct CourseSubscription {
 pub struct DeckSubscription {
     #[foreign(type = "User")]
     pub user_id: i32,
-    #[foreign(type = "Course")]
+    #[foreign(type = "Deck")]
     pub deck_id: i32,
 }
 
-pub trait ToJson<T> {
-    fn json(self) -> Option<rocket::serde::json::Json<T>>;
+/// A login on one device, kept alive by rotating refresh tokens
+#[model(table = "sessions")]
+#[derive(Related)]
+pub struct Session {
+    #[foreign(type = "User")]
+    pub user_id: i32,
+    /// SHA-256 of the current refresh token's secret
+    #[serde(skip_serializing)]
+    pub refresh_hash: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// A password reset link sent by email, usable once
+#[model(table = "password_resets")]
+#[derive(Related)]
+pub struct PasswordReset {
+    #[foreign(type = "User")]
+    pub user_id: i32,
+    /// SHA-256 of the token in the link
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+/// TOTP secret of a user. Login asks for a code once `enabled` is set by confirming the first one.
+#[model(table = "two_factor")]
+#[derive(Related)]
+pub struct TwoFactor {
+    #[foreign(type = "User")]
+    pub user_id: i32,
+    /// Base32 encoded, as shown to authenticator apps
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub enabled: bool,
+    /// Time step of the last accepted code, so that no code works twice
+    pub last_step: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Single-use code for logging in without the authenticator, hashed like a password
+#[model(table = "recovery_codes")]
+#[derive(Related)]
+pub struct RecoveryCode {
+    #[foreign(type = "User")]
+    pub user_id: i32,
+    #[serde(skip_serializing)]
+    pub code_hash: String,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+/// An account at an OpenID Connect provider that can sign in as the user
+#[model(table = "identities")]
+#[derive(Related)]
+pub struct Identity {
+    #[foreign(type = "User")]
+    pub user_id: i32,
+    /// Name of the provider in the `oidc` configuration
+    pub provider: String,
+    /// The provider's id of the account (`sub` claim)
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A long-lived key for scripts, sent as `Authorization: ApiKey <key>`
+#[model(table = "api_keys")]
+#[derive(Related)]
+pub struct ApiKey {
+    #[foreign(type = "User")]
+    pub user_id: i32,
+    pub name: String,
+    /// Start of the key, shown to tell keys apart
+    pub prefix: String,
+    /// SHA-256 of the whole key
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    /// What the key may do, e.g. `decks:read` or `cards:write`
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Revoked access tokens: a single token by `jti`, or all tokens of a user issued up to `revoked_at`.
+/// Not a foreign key, so revocations outlive deleted users until the tokens would have expired.
+#[model(table = "token_revocations")]
+pub struct Revocation {
+    pub user_id: i32,
+    pub jti: Option<String>,
+    pub revoked_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+fn default_role() -> String {
+    "user".to_string()
+}
+
+/// Deserializes a field of a generated patch, so that a present `null` becomes `Some(None)`
+/// rather than being mistaken for an absent field
+pub fn present<'de, T: Deserialize<'de>, D: Deserializer<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Error of the generated update routes
+pub enum Rejected<T> {
+    /// The update was based on a stale version; carries the current copy
+    Conflict(T),
+    Failed(Status, String),
 }
 
-impl<T> ToJson<T> for Option<T> {
-    fn json(self) -> Option<rocket::serde::json::Json<T>> {
+impl<'r, T: Serialize> Responder<'r, 'static> for Rejected<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
         match self {
-            Some(model) => Some(rocket::serde::json::Json(model)),
-            None => None,
+            Rejected::Conflict(current) => Custom(Status::Conflict, Json(current)).respond_to(request),
+            Rejected::Failed(status, message) => Custom(status, message).respond_to(request),
+        }
+    }
+}
+
+/// One page of a generated list route
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+    pub pages: i64,
+}
+
+/// Pagination, sorting and filters of a generated list route, checked against the model's columns
+#[derive(Debug)]
+pub struct ListQuery {
+    pub page: i64,
+    pub per_page: i64,
+    pub sort: String,
+    pub descending: bool,
+    pub filters: Vec<(String, String)>,
+}
+
+impl ListQuery {
+    /// Reads `page`, `per_page` and `sort` (prefixed with `-` for descending order);
+    /// every other parameter filters on the column of the same name.
+    pub fn parse(mut params: HashMap<String, String>, columns: &[&str]) -> Result<Self, String> {
+        let number = |value: Option<String>, name: &str| -> Result<Option<i64>, String> {
+            value.map(|v| v.parse().map_err(|_| format!("{} must be a number.", name))).transpose()
+        };
+        let page = number(params.remove("page"), "page")?.unwrap_or(1).max(1);
+        if page > MAXIMUM_PAGE {
+            return Err(format!("page must be at most {}.", MAXIMUM_PAGE));
         }
+        let per_page = number(params.remove("per_page"), "per_page")?
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAXIMUM_PER_PAGE);
+
+        let sort = params.remove("sort").unwrap_or_else(|| "id".to_string());
+        let (sort, descending) = match sort.strip_prefix('-') {
+            Some(sort) => (sort.to_string(), true),
+            None => (sort, false),
+        };
+        if !columns.contains(&sort.as_str()) {
+            return Err(format!("Cannot sort by {}.", sort));
+        }
+
+        let mut filters: Vec<_> = params.into_iter().collect();
+        filters.sort();
+        if let Some((column, _)) = filters.iter().find(|(column, _)| !columns.contains(&column.as_str())) {
+            return Err(format!("Cannot filter by {}.", column));
+        }
+
+        Ok(ListQuery { page, per_page, sort, descending, filters })
+    }
+
+    /// WHERE clause combining the access `scope` (which may use the user id as $1) with the filters,
+    /// whose values are bound from $2 on in the order of `values()`
+    pub fn condition(&self, scope: &str) -> String {
+        let mut condition = format!("({})", scope);
+        let mut n = 1;
+        for (column, value) in &self.filters {
+            if value == "null" {
+                condition.push_str(&format!(" AND {} IS NULL", column));
+            } else {
+                n += 1;
+                condition.push_str(&format!(" AND {}::text = ${}", column, n));
+            }
+        }
+        condition
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &String> {
+        self.filters.iter().map(|(_, value)| value).filter(|value| *value != "null")
+    }
+
+    pub fn order(&self) -> String {
+        format!("ORDER BY {} {}, id LIMIT {} OFFSET {}",
+            self.sort,
+            if self.descending { "DESC" } else { "ASC" },
+            self.per_page,
+            (self.page - 1) * self.per_page)
     }
 }
@@ -0,0 +1,502 @@
+use super::models::{History, Review, Settings};
+use chrono::{DateTime, Duration, Utc};
+use rocket::serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Ease factor given to cards that have never been reviewed (SM-2)
+const INITIAL_EASE: f64 = 2.5;
+/// Lowest ease factor a card can fall to (SM-2)
+const MINIMUM_EASE: f64 = 1.3;
+/// Probability of recall the FSRS scheduler aims for when picking intervals
+const DESIRED_RETENTION: f64 = 0.9;
+/// Longest interval either scheduler will hand out, in days
+const MAXIMUM_INTERVAL: i32 = 36500;
+
+/// FSRS v4.5 default model weights
+const FSRS_WEIGHTS: [f64; 17] = [
+    0.4872, 1.4003, 3.7145, 13.8206, 5.1618, 1.2298, 0.8975, 0.031, 1.6474,
+    0.1367, 1.0461, 2.1072, 0.0793, 0.3246, 1.587, 0.2272, 2.8755,
+];
+const FSRS_DECAY: f64 = -0.5;
+const FSRS_FACTOR: f64 = 19.0 / 81.0;
+
+/// How well a learner recalled a card
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Grade {
+    Again = 1,
+    Hard = 2,
+    Good = 3,
+    Easy = 4,
+}
+
+impl Grade {
+    pub fn passed(&self) -> bool {
+        *self != Grade::Again
+    }
+}
+
+impl TryFrom<i32> for Grade {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Grade::Again),
+            2 => Ok(Grade::Hard),
+            3 => Ok(Grade::Good),
+            4 => Ok(Grade::Easy),
+            _ => Err(format!("invalid grade {}", value)),
+        }
+    }
+}
+
+/// Scheduling fields persisted alongside each `History` row
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SchedulingState {
+    pub ease: f64,
+    pub interval: i32,
+    pub repetitions: i32,
+    pub lapses: i32,
+    pub stability: f64,
+    pub difficulty: f64,
+    pub due: DateTime<Utc>,
+    pub last_review: Option<DateTime<Utc>>,
+}
+
+impl SchedulingState {
+    /// State of a card that has never been reviewed
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            ease: INITIAL_EASE,
+            interval: 0,
+            repetitions: 0,
+            lapses: 0,
+            stability: 0.0,
+            difficulty: 0.0,
+            due: now,
+            last_review: None,
+        }
+    }
+
+    fn due_in_days(mut self, interval: i32, now: DateTime<Utc>) -> Self {
+        self.interval = interval.clamp(1, MAXIMUM_INTERVAL);
+        self.due = now + Duration::days(self.interval as i64);
+        self
+    }
+}
+
+pub trait Scheduler {
+    /// Computes the state of a card after it was answered with `grade` at `now`
+    fn schedule(&self, state: &SchedulingState, grade: Grade, now: DateTime<Utc>) -> SchedulingState;
+}
+
+/// Scheduling algorithm a user has selected in their `Settings`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    #[default]
+    Sm2,
+    Fsrs,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sm2" | "sm-2" => Ok(Algorithm::Sm2),
+            "fsrs" => Ok(Algorithm::Fsrs),
+            _ => Err(format!("unknown scheduling algorithm '{}'", s)),
+        }
+    }
+}
+
+impl Scheduler for Algorithm {
+    fn schedule(&self, state: &SchedulingState, grade: Grade, now: DateTime<Utc>) -> SchedulingState {
+        match self {
+            Algorithm::Sm2 => Sm2.schedule(state, grade, now),
+            Algorithm::Fsrs => Fsrs.schedule(state, grade, now),
+        }
+    }
+}
+
+/// SuperMemo 2, extended to four grades the same way Anki does
+pub struct Sm2;
+
+impl Scheduler for Sm2 {
+    fn schedule(&self, state: &SchedulingState, grade: Grade, now: DateTime<Utc>) -> SchedulingState {
+        let mut next = state.clone();
+        next.last_review = Some(now);
+
+        // SM-2 quality of response: again = 2, hard = 3, good = 4, easy = 5
+        let q = grade as i32 + 1;
+        let penalty = (5 - q) as f64;
+        next.ease = (state.ease + 0.1 - penalty * (0.08 + penalty * 0.02)).max(MINIMUM_EASE);
+
+        if !grade.passed() {
+            // Relearn the card shortly, and start counting repetitions again
+            next.repetitions = 0;
+            next.lapses += 1;
+            next.interval = 0;
+            next.due = now + Duration::minutes(10);
+            return next;
+        }
+
+        next.repetitions += 1;
+        let interval = match next.repetitions {
+            1 => 1,
+            2 => 6,
+            _ => (state.interval as f64 * next.ease).round() as i32,
+        };
+        next.due_in_days(interval, now)
+    }
+}
+
+/// Free Spaced Repetition Scheduler (FSRS v4.5) with the default weights
+pub struct Fsrs;
+
+impl Fsrs {
+    fn initial_stability(grade: Grade) -> f64 {
+        FSRS_WEIGHTS[grade as usize - 1]
+    }
+
+    fn initial_difficulty(grade: Grade) -> f64 {
+        (FSRS_WEIGHTS[4] - (grade as i32 - 3) as f64 * FSRS_WEIGHTS[5]).clamp(1.0, 10.0)
+    }
+
+    fn retrievability(elapsed_days: f64, stability: f64) -> f64 {
+        (1.0 + FSRS_FACTOR * elapsed_days / stability).powf(FSRS_DECAY)
+    }
+
+    fn interval(stability: f64) -> i32 {
+        (stability / FSRS_FACTOR * (DESIRED_RETENTION.powf(1.0 / FSRS_DECAY) - 1.0)).round() as i32
+    }
+
+    fn next_difficulty(difficulty: f64, grade: Grade) -> f64 {
+        let next = difficulty - FSRS_WEIGHTS[6] * (grade as i32 - 3) as f64;
+        // Mean reversion towards the difficulty of a card first answered "good"
+        let reverted = FSRS_WEIGHTS[7] * Self::initial_difficulty(Grade::Good) + (1.0 - FSRS_WEIGHTS[7]) * next;
+        reverted.clamp(1.0, 10.0)
+    }
+
+    fn recall_stability(difficulty: f64, stability: f64, retrievability: f64, grade: Grade) -> f64 {
+        let hard_penalty = if grade == Grade::Hard { FSRS_WEIGHTS[15] } else { 1.0 };
+        let easy_bonus = if grade == Grade::Easy { FSRS_WEIGHTS[16] } else { 1.0 };
+        stability * (FSRS_WEIGHTS[8].exp()
+            * (11.0 - difficulty)
+            * stability.powf(-FSRS_WEIGHTS[9])
+            * ((FSRS_WEIGHTS[10] * (1.0 - retrievability)).exp() - 1.0)
+            * hard_penalty
+            * easy_bonus
+            + 1.0)
+    }
+
+    fn forget_stability(difficulty: f64, stability: f64, retrievability: f64) -> f64 {
+        FSRS_WEIGHTS[11]
+            * difficulty.powf(-FSRS_WEIGHTS[12])
+            * ((stability + 1.0).powf(FSRS_WEIGHTS[13]) - 1.0)
+            * (FSRS_WEIGHTS[14] * (1.0 - retrievability)).exp()
+    }
+}
+
+impl Scheduler for Fsrs {
+    fn schedule(&self, state: &SchedulingState, grade: Grade, now: DateTime<Utc>) -> SchedulingState {
+        let mut next = state.clone();
+        next.last_review = Some(now);
+
+        if state.stability <= 0.0 {
+            // First review of a card (or one previously scheduled by SM-2)
+            next.stability = Self::initial_stability(grade);
+            next.difficulty = Self::initial_difficulty(grade);
+        } else {
+            let elapsed = state.last_review
+                .map(|ts| (now - ts).num_seconds().max(0) as f64 / 86400.0)
+                .unwrap_or(0.0);
+            let retrievability = Self::retrievability(elapsed, state.stability);
+            next.difficulty = Self::next_difficulty(state.difficulty, grade);
+            next.stability = if grade.passed() {
+                Self::recall_stability(state.difficulty, state.stability, retrievability, grade)
+            } else {
+                Self::forget_stability(state.difficulty, state.stability, retrievability)
+            };
+        }
+
+        if !grade.passed() {
+            next.repetitions = 0;
+            next.lapses += 1;
+            next.interval = 0;
+            next.due = now + Duration::minutes(10);
+            return next;
+        }
+
+        next.repetitions += 1;
+        let interval = Self::interval(next.stability);
+        next.due_in_days(interval, now)
+    }
+}
+
+impl Settings {
+    /// The scheduling algorithm selected by the user, falling back to SM-2
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm.as_ref()
+            .and_then(|a| a.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+impl History {
+    /// A history row for a card the user has not reviewed yet
+    pub fn unseen(user_id: i32, card_id: i32, now: DateTime<Utc>) -> Self {
+        let state = SchedulingState::new(now);
+        Self {
+            id: None,
+            user_id,
+            card_id,
+            ts: now,
+            num_confident: 0,
+            num_correct: 0,
+            num_wrong: 0,
+            ease: state.ease,
+            interval: state.interval,
+            repetitions: state.repetitions,
+            lapses: state.lapses,
+            stability: state.stability,
+            difficulty: state.difficulty,
+            due: state.due,
+            time_spent: 0,
+        }
+    }
+
+    pub fn state(&self) -> SchedulingState {
+        let reviewed = self.num_confident + self.num_correct + self.num_wrong > 0;
+        SchedulingState {
+            ease: self.ease,
+            interval: self.interval,
+            repetitions: self.repetitions,
+            lapses: self.lapses,
+            stability: self.stability,
+            difficulty: self.difficulty,
+            due: self.due,
+            last_review: reviewed.then_some(self.ts),
+        }
+    }
+
+    pub fn set_state(&mut self, state: SchedulingState) {
+        self.ease = state.ease;
+        self.interval = state.interval;
+        self.repetitions = state.repetitions;
+        self.lapses = state.lapses;
+        self.stability = state.stability;
+        self.difficulty = state.difficulty;
+        self.due = state.due;
+        if let Some(ts) = state.last_review {
+            self.ts = ts;
+        }
+    }
+
+    fn counter(&mut self, grade: Grade) -> &mut i32 {
+        match grade {
+            Grade::Again => &mut self.num_wrong,
+            Grade::Hard | Grade::Good => &mut self.num_correct,
+            Grade::Easy => &mut self.num_confident,
+        }
+    }
+
+    /// Records an answer and reschedules the card with `algorithm`
+    pub fn review(&mut self, grade: Grade, algorithm: Algorithm, now: DateTime<Utc>) -> SchedulingState {
+        let state = algorithm.schedule(&self.state(), grade, now);
+        *self.counter(grade) += 1;
+        self.set_state(state.clone());
+        state
+    }
+}
+
+impl Review {
+    /// Log entry for answering a card whose history was `previous` (`None` for a new card)
+    pub fn record(user_id: i32, card_id: i32, grade: Grade, response_time: i32, session_id: Option<i32>, previous: Option<&History>, now: DateTime<Utc>) -> Self {
+        Self {
+            id: None,
+            user_id,
+            card_id,
+            ts: now,
+            grade: grade as i32,
+            response_time,
+            undone: false,
+            session_id,
+            prev_ts: previous.map(|h| h.ts),
+            prev_ease: previous.map(|h| h.ease),
+            prev_interval: previous.map(|h| h.interval),
+            prev_repetitions: previous.map(|h| h.repetitions),
+            prev_lapses: previous.map(|h| h.lapses),
+            prev_stability: previous.map(|h| h.stability),
+            prev_difficulty: previous.map(|h| h.difficulty),
+            prev_due: previous.map(|h| h.due),
+        }
+    }
+
+    /// Whether the card had never been reviewed before this entry
+    pub fn was_new(&self) -> bool {
+        self.prev_due.is_none()
+    }
+
+    /// Rolls `history` back to how it was before this review.
+    /// Returns false if the card was new, in which case the history row should be removed instead.
+    pub fn restore(&self, history: &mut History) -> bool {
+        if self.was_new() {
+            return false;
+        }
+        if let Ok(grade) = Grade::try_from(self.grade) {
+            let counter = history.counter(grade);
+            *counter = (*counter - 1).max(0);
+        }
+        history.time_spent = (history.time_spent - self.response_time as i64).max(0);
+        history.ts = self.prev_ts.unwrap_or(history.ts);
+        history.ease = self.prev_ease.unwrap_or(history.ease);
+        history.interval = self.prev_interval.unwrap_or(history.interval);
+        history.repetitions = self.prev_repetitions.unwrap_or(history.repetitions);
+        history.lapses = self.prev_lapses.unwrap_or(history.lapses);
+        history.stability = self.prev_stability.unwrap_or(history.stability);
+        history.difficulty = self.prev_difficulty.unwrap_or(history.difficulty);
+        history.due = self.prev_due.unwrap_or(history.due);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn day(n: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000, 0).unwrap() + Duration::days(n)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-4, "expected {}, got {}", expected, actual);
+    }
+
+    /// Answers a new card with `grades`, each one on the day the previous answer made it due
+    fn answer(scheduler: &impl Scheduler, grades: &[Grade]) -> SchedulingState {
+        let mut state = SchedulingState::new(day(0));
+        for grade in grades {
+            state = scheduler.schedule(&state, *grade, state.due);
+        }
+        state
+    }
+
+    #[test]
+    fn sm2_grows_intervals_with_ease() {
+        let state = answer(&Sm2, &[Grade::Good]);
+        assert_eq!((state.interval, state.repetitions), (1, 1));
+        assert_close(state.ease, 2.5);
+
+        let state = answer(&Sm2, &[Grade::Good, Grade::Good, Grade::Good]);
+        assert_eq!((state.interval, state.repetitions), (15, 3));
+
+        // Easy raises the ease by 0.1, hard lowers it by 0.14
+        let state = answer(&Sm2, &[Grade::Good, Grade::Good, Grade::Good, Grade::Easy]);
+        assert_close(state.ease, 2.6);
+        assert_eq!(state.interval, 39);
+
+        let state = answer(&Sm2, &[Grade::Good, Grade::Good, Grade::Good, Grade::Easy, Grade::Hard]);
+        assert_close(state.ease, 2.46);
+        assert_eq!(state.interval, 96);
+        assert_eq!(state.due, day(1 + 6 + 15 + 39 + 96));
+    }
+
+    #[test]
+    fn sm2_relearns_lapsed_cards() {
+        let before = answer(&Sm2, &[Grade::Good, Grade::Good, Grade::Good]);
+        let state = Sm2.schedule(&before, Grade::Again, before.due);
+        assert_eq!((state.interval, state.repetitions, state.lapses), (0, 0, 1));
+        assert_close(state.ease, 2.18);
+        assert_eq!(state.due, before.due + Duration::minutes(10));
+
+        // Starts over at one day, with the lowered ease kept
+        let state = Sm2.schedule(&state, Grade::Good, state.due);
+        assert_eq!((state.interval, state.repetitions, state.lapses), (1, 1, 1));
+    }
+
+    #[test]
+    fn sm2_keeps_minimum_ease() {
+        let state = answer(&Sm2, &[Grade::Again; 8]);
+        assert_close(state.ease, MINIMUM_EASE);
+        assert_eq!(state.lapses, 8);
+    }
+
+    #[test]
+    fn fsrs_starts_from_grade() {
+        let state = answer(&Fsrs, &[Grade::Good]);
+        assert_close(state.stability, 3.7145);
+        assert_close(state.difficulty, 5.1618);
+        // At 90% desired retention the interval is the stability
+        assert_eq!(state.interval, 4);
+
+        let state = answer(&Fsrs, &[Grade::Easy]);
+        assert_close(state.stability, 13.8206);
+        assert_close(state.difficulty, 3.932);
+        assert_eq!(state.interval, 14);
+
+        let state = answer(&Fsrs, &[Grade::Again]);
+        assert_close(state.stability, 0.4872);
+        assert_close(state.difficulty, 7.6214);
+        assert_eq!((state.interval, state.lapses), (0, 1));
+        assert_eq!(state.due, day(0) + Duration::minutes(10));
+    }
+
+    #[test]
+    fn fsrs_updates_stability_on_review() {
+        let state = answer(&Fsrs, &[Grade::Good, Grade::Good]);
+        assert_close(state.stability, 14.8081);
+        assert_close(state.difficulty, 5.1618);
+        assert_eq!((state.interval, state.repetitions), (15, 2));
+
+        let state = answer(&Fsrs, &[Grade::Good, Grade::Good, Grade::Again]);
+        assert_close(state.stability, 3.1493);
+        assert_close(state.difficulty, 6.9012);
+        assert_eq!((state.interval, state.repetitions, state.lapses), (0, 0, 1));
+    }
+
+    #[test]
+    fn fsrs_recall_is_desired_retention_after_stability() {
+        assert_close(Fsrs::retrievability(0.0, 7.0), 1.0);
+        assert_close(Fsrs::retrievability(7.0, 7.0), DESIRED_RETENTION);
+    }
+
+    #[test]
+    fn intervals_stay_within_maximum() {
+        let mut state = SchedulingState::new(day(0));
+        state.repetitions = 5;
+        state.interval = MAXIMUM_INTERVAL;
+        assert_eq!(Sm2.schedule(&state, Grade::Easy, day(0)).interval, MAXIMUM_INTERVAL);
+    }
+
+    #[test]
+    fn restore_undoes_review() {
+        let mut history = History::unseen(1, 1, day(0));
+        history.review(Grade::Good, Algorithm::Fsrs, day(0));
+        let before = history.clone();
+
+        let review = Review::record(1, 1, Grade::Hard, 4000, None, Some(&before), day(4));
+        history.time_spent += 4000;
+        history.review(Grade::Hard, Algorithm::Fsrs, day(4));
+        assert_eq!(history.num_correct, 2);
+        assert!(history.due > before.due);
+
+        assert!(review.restore(&mut history));
+        assert_eq!(history.state(), before.state());
+        assert_eq!(history.ts, before.ts);
+        assert_eq!((history.num_correct, history.num_wrong, history.num_confident), (1, 0, 0));
+        assert_eq!(history.time_spent, before.time_spent);
+    }
+
+    #[test]
+    fn restore_leaves_new_cards_to_be_removed() {
+        let mut history = History::unseen(1, 1, day(0));
+        let review = Review::record(1, 1, Grade::Good, 1000, None, None, day(0));
+        history.review(Grade::Good, Algorithm::Sm2, day(0));
+
+        assert!(review.was_new());
+        assert!(!review.restore(&mut history));
+    }
+}
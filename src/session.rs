@@ -0,0 +1,224 @@
+use chrono::{DateTime, Utc};
+use rand_core::{OsRng, RngCore};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::status::Custom;
+use rocket::serde::{Deserialize, Serialize, json::Json};
+use rocket::State;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Acquire, PgConnection};
+use sha2::{Digest, Sha256};
+use super::Db;
+use super::auth::{AuthConfig, AuthenticatedUser};
+use super::models::*;
+use super::user::JwtToken;
+
+fn error(_: sqlx::Error) -> Custom<String> {
+    Custom(
+        Status::InternalServerError,
+        "Could not update sessions. Please try again.".to_string(),
+    )
+}
+
+fn invalid() -> Custom<String> {
+    Custom(
+        Status::Unauthorized,
+        "Invalid refresh token.".to_string(),
+    )
+}
+
+/// Device a login comes from, shown in the list of sessions
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientInfo {
+            user_agent: request.headers().get_one("User-Agent").map(|a| a.to_string()),
+            ip: request.client_ip().map(|ip| ip.to_string()),
+        })
+    }
+}
+
+/// Random part of a refresh or password reset token, of which only the hash is stored
+pub(crate) fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn digest(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Signs an access token for the session, along with the refresh token `<session id>.<secret>`
+fn issue(config: &AuthConfig, user: User, session: &Session, secret: String) -> Result<JwtToken, Custom<String>> {
+    let session_id = session.id.unwrap();
+    Ok(JwtToken {
+        token: AuthenticatedUser::from_user(&user, session_id).into_token(config)?,
+        refresh_token: format!("{}.{}", session_id, secret),
+        expires_in: config.lifetime().num_seconds(),
+    })
+}
+
+/// Opens a new session for a user who just logged in or registered
+pub(crate) async fn start(conn: &mut PgConnection, config: &AuthConfig, user: User, client: ClientInfo) -> Result<JwtToken, Custom<String>> {
+    let now = Utc::now();
+    let secret = generate_secret();
+
+    let session = Session::new_from(
+        &user,
+        digest(&secret),
+        client.user_agent,
+        client.ip,
+        now,
+        now,
+        now + config.refresh_lifetime(),
+        None,
+    ).unwrap().save_in(conn).await.map_err(error)?;
+
+    issue(config, user, &session, secret)
+}
+
+/// Revokes every active session of a user, returning how many there were
+pub(crate) async fn revoke_all(conn: &mut PgConnection, user_id: i32) -> Result<u64, sqlx::Error> {
+    sqlx::query(format!("UPDATE {} SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL", Session::table()).as_str())
+        .bind(user_id)
+        .execute(conn)
+        .await
+        .map(|r| r.rows_affected())
+}
+
+async fn revoke(conn: &mut PgConnection, user_id: i32, id: i32) -> Result<bool, sqlx::Error> {
+    sqlx::query(format!("UPDATE {} SET revoked_at = now() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL", Session::table()).as_str())
+        .bind(id)
+        .bind(user_id)
+        .execute(conn)
+        .await
+        .map(|r| r.rows_affected() == 1)
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[post("/refresh", data = "<request>")]
+pub async fn refresh(mut db: Connection<Db>, config: &State<AuthConfig>, request: Json<RefreshRequest>) -> Result<Json<JwtToken>, Custom<String>> {
+    let (id, secret) = request.refresh_token.split_once('.').ok_or_else(invalid)?;
+    let id: i32 = id.parse().map_err(|_| invalid())?;
+    let now = Utc::now();
+
+    let mut tx = (&mut *db).begin().await.map_err(error)?;
+
+    let mut session = sqlx::query(format!("SELECT * FROM {} WHERE id = $1 FOR UPDATE", Session::table()).as_str())
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?
+        .map(Session::from)
+        .ok_or_else(invalid)?;
+
+    if session.revoked_at.is_some() || session.expires_at < now {
+        return Err(invalid());
+    }
+
+    if session.refresh_hash != digest(secret) {
+        // A refresh token that was already rotated away is being reused, so it may have been stolen
+        revoke(&mut *tx, session.user_id, id).await.map_err(error)?;
+        tx.commit().await.map_err(error)?;
+        config.revoke_session(id);
+        return Err(invalid());
+    }
+
+    let user = sqlx::query(format!("SELECT * FROM {} WHERE id = $1", User::table()).as_str())
+        .bind(session.user_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?
+        .map(User::from)
+        .ok_or_else(invalid)?;
+
+    if user.disabled {
+        return Err(Custom(
+            Status::Forbidden,
+            "This account has been disabled.".to_string(),
+        ));
+    }
+
+    let secret = generate_secret();
+    session.refresh_hash = digest(&secret);
+    session.last_used_at = now;
+    session.expires_at = now + config.refresh_lifetime();
+    let session = session.save_in(&mut *tx).await.map_err(error)?;
+
+    tx.commit().await.map_err(error)?;
+
+    Ok(Json(issue(config, user, &session, secret)?))
+}
+
+#[post("/logout")]
+pub async fn logout(mut db: Connection<Db>, config: &State<AuthConfig>, user: AuthenticatedUser) -> Result<Json<bool>, Custom<String>> {
+    config.revoke(&mut *db, &user).await.map_err(error)?;
+    match user.session_id() {
+        None => Ok(Json(false)),
+        Some(id) => Ok(Json(revoke(&mut *db, user.id(), id).await.map_err(error)?)),
+    }
+}
+
+#[post("/logout_all")]
+pub async fn logout_all(mut db: Connection<Db>, config: &State<AuthConfig>, user: AuthenticatedUser) -> Result<Json<u64>, Custom<String>> {
+    config.revoke_user(&mut *db, user.id()).await.map_err(error)?;
+    Ok(Json(revoke_all(&mut *db, user.id()).await.map_err(error)?))
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SessionInfo {
+    pub id: i32,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Whether this is the session of the requesting token
+    pub current: bool,
+}
+
+#[get("/sessions")]
+pub async fn list_sessions(mut db: Connection<Db>, user: AuthenticatedUser) -> Result<Json<Vec<SessionInfo>>, Custom<String>> {
+    let sessions = sqlx::query(format!(
+            "SELECT * FROM {} WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > now() ORDER BY last_used_at DESC",
+            Session::table()).as_str())
+        .bind(user.id())
+        .fetch_all(&mut *db)
+        .await
+        .map_err(error)?;
+
+    Ok(Json(sessions.into_iter().map(Session::from).map(|session| {
+        let id = session.id.unwrap();
+        SessionInfo {
+            id,
+            user_agent: session.user_agent,
+            ip: session.ip,
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+            expires_at: session.expires_at,
+            current: user.session_id() == Some(id),
+        }
+    }).collect()))
+}
+
+#[delete("/sessions/<id>")]
+pub async fn delete_session(mut db: Connection<Db>, config: &State<AuthConfig>, user: AuthenticatedUser, id: i32) -> Result<Option<()>, Custom<String>> {
+    let revoked = revoke(&mut *db, user.id(), id).await.map_err(error)?;
+    if revoked {
+        config.revoke_session(id);
+    }
+    Ok(revoked.then_some(()))
+}
@@ -0,0 +1,180 @@
+use chrono::{Duration, NaiveDate, Utc};
+use rocket::futures::TryStreamExt;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::{Serialize, json::Json};
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, PgConnection, Row};
+use super::Db;
+use super::auth::AuthenticatedUser;
+use super::models::*;
+
+/// Days covered by the heatmap and retention endpoints when no range is given
+const DEFAULT_RANGE: i64 = 365;
+/// Days covered by the forecast endpoint when no range is given
+const DEFAULT_FORECAST: i64 = 30;
+/// Longest range in days the endpoints accept; longer ones are shortened to it
+const MAX_RANGE: i64 = 3650;
+/// Interval (in days) from which a card counts as mature rather than young
+const MATURE_INTERVAL: i32 = 21;
+
+fn error(_: sqlx::Error) -> Custom<String> {
+    Custom(
+        Status::InternalServerError,
+        "Could not load statistics. Please try again.".to_string(),
+    )
+}
+
+/// The requested number of days, kept between one day and `MAX_RANGE`
+fn range(days: Option<i64>, default: i64) -> i64 {
+    days.unwrap_or(default).clamp(1, MAX_RANGE)
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DayCount {
+    pub day: NaiveDate,
+    pub count: i64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Streak {
+    pub current: i64,
+    pub longest: i64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Retention {
+    /// Share of reviews of already learned cards that were remembered, between 0 and 1
+    pub retention: Option<f64>,
+    pub young: Option<f64>,
+    pub mature: Option<f64>,
+    pub reviews: i64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Summary {
+    pub reviews: i64,
+    pub cards: i64,
+    /// Total time spent answering, in milliseconds
+    pub time_spent: i64,
+    pub streak: Streak,
+    pub retention: Retention,
+}
+
+async fn streak(conn: &mut PgConnection, user_id: i32) -> Result<Streak, sqlx::Error> {
+    // Consecutive study days share the same (day - row number), which groups them into one streak
+    let row = sqlx::query(format!(
+            "WITH days AS (
+                 SELECT DISTINCT (ts AT TIME ZONE 'UTC')::date AS day FROM {} WHERE user_id = $1 AND NOT undone
+             ), streaks AS (
+                 SELECT MAX(day) AS last_day, COUNT(*) AS length
+                 FROM (SELECT day, day - (ROW_NUMBER() OVER (ORDER BY day))::int AS streak FROM days) d
+                 GROUP BY streak
+             )
+             SELECT COALESCE(MAX(length) FILTER (WHERE last_day >= $2), 0), COALESCE(MAX(length), 0) FROM streaks",
+            Review::table()).as_str())
+        .bind(user_id)
+        // A streak is still current if the user has not studied yet today
+        .bind((Utc::now() - Duration::days(1)).date_naive())
+        .fetch_one(conn)
+        .await?;
+
+    Ok(Streak {
+        current: row.get(0),
+        longest: row.get(1),
+    })
+}
+
+async fn retention(conn: &mut PgConnection, user_id: i32, days: i64) -> Result<Retention, sqlx::Error> {
+    // Only answers to cards that had already graduated from (re)learning count towards true retention
+    let row = sqlx::query(format!(
+            "SELECT AVG((grade > 1)::int)::float8,
+                    (AVG((grade > 1)::int) FILTER (WHERE prev_interval < $3))::float8,
+                    (AVG((grade > 1)::int) FILTER (WHERE prev_interval >= $3))::float8,
+                    COUNT(*)
+             FROM {} WHERE user_id = $1 AND NOT undone AND ts >= $2 AND prev_interval >= 1",
+            Review::table()).as_str())
+        .bind(user_id)
+        .bind(Utc::now() - Duration::days(days))
+        .bind(MATURE_INTERVAL)
+        .fetch_one(conn)
+        .await?;
+
+    Ok(Retention {
+        retention: row.get(0),
+        young: row.get(1),
+        mature: row.get(2),
+        reviews: row.get(3),
+    })
+}
+
+#[get("/")]
+pub async fn summary(mut db: Connection<Db>, user: AuthenticatedUser) -> Result<Json<Summary>, Custom<String>> {
+    let totals = sqlx::query(format!(
+            "SELECT COUNT(*), COUNT(DISTINCT card_id), COALESCE(SUM(response_time), 0)
+             FROM {} WHERE user_id = $1 AND NOT undone", Review::table()).as_str())
+        .bind(user.id())
+        .fetch_one(&mut *db)
+        .await
+        .map_err(error)?;
+
+    Ok(Json(Summary {
+        reviews: totals.get(0),
+        cards: totals.get(1),
+        time_spent: totals.get(2),
+        streak: streak(&mut *db, user.id()).await.map_err(error)?,
+        retention: retention(&mut *db, user.id(), DEFAULT_RANGE).await.map_err(error)?,
+    }))
+}
+
+#[get("/heatmap?<days>")]
+pub async fn heatmap(mut db: Connection<Db>, user: AuthenticatedUser, days: Option<i64>) -> Result<Json<Vec<DayCount>>, Custom<String>> {
+    let since = Utc::now() - Duration::days(range(days, DEFAULT_RANGE));
+    let counts = sqlx::query(format!(
+            "SELECT (ts AT TIME ZONE 'UTC')::date AS day, COUNT(*) AS count
+             FROM {} WHERE user_id = $1 AND NOT undone AND ts >= $2
+             GROUP BY day ORDER BY day", Review::table()).as_str())
+        .bind(user.id())
+        .bind(since)
+        .fetch(&mut *db)
+        .map_ok(|r| DayCount { day: r.get("day"), count: r.get("count") })
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(error)?;
+
+    Ok(Json(counts))
+}
+
+#[get("/streak")]
+pub async fn current_streak(mut db: Connection<Db>, user: AuthenticatedUser) -> Result<Json<Streak>, Custom<String>> {
+    Ok(Json(streak(&mut *db, user.id()).await.map_err(error)?))
+}
+
+#[get("/retention?<days>")]
+pub async fn true_retention(mut db: Connection<Db>, user: AuthenticatedUser, days: Option<i64>) -> Result<Json<Retention>, Custom<String>> {
+    Ok(Json(retention(&mut *db, user.id(), range(days, DEFAULT_RANGE)).await.map_err(error)?))
+}
+
+#[get("/forecast?<days>")]
+pub async fn forecast(mut db: Connection<Db>, user: AuthenticatedUser, days: Option<i64>) -> Result<Json<Vec<DayCount>>, Custom<String>> {
+    let now = Utc::now();
+    // Overdue cards are counted as due today
+    let counts = sqlx::query(format!(
+            "SELECT (GREATEST(due, $2) AT TIME ZONE 'UTC')::date AS day, COUNT(*) AS count
+             FROM {} WHERE user_id = $1 AND due < $3
+             GROUP BY day ORDER BY day", History::table()).as_str())
+        .bind(user.id())
+        .bind(now)
+        .bind(now + Duration::days(range(days, DEFAULT_FORECAST)))
+        .fetch(&mut *db)
+        .map_ok(|r| DayCount { day: r.get("day"), count: r.get("count") })
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(error)?;
+
+    Ok(Json(counts))
+}
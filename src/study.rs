@@ -0,0 +1,443 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use rocket::futures::TryStreamExt;
+use rocket::http::Status;
+use rocket::response::status::{Created, Custom};
+use rocket::serde::{Deserialize, Serialize, json::Json};
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Acquire, Row};
+use super::Db;
+use super::auth::AuthenticatedUser;
+use super::models::*;
+use super::scheduler::Grade;
+
+/// New cards introduced per day unless the user's `Settings` say otherwise
+const DEFAULT_NEW_CARDS_PER_DAY: i32 = 20;
+/// Reviews shown per day unless the user's `Settings` say otherwise
+const DEFAULT_REVIEWS_PER_DAY: i32 = 200;
+
+/// How far back (in minutes) a review outside of a `StudySession` can be undone
+const UNDO_WINDOW: i64 = 30;
+
+/// SQL selecting the ids of every deck the user ($1) may study: decks they own,
+/// decks they subscribe to, and decks belonging to courses they own or subscribe to.
+/// Optionally narrowed down to a single deck ($2) or the decks of a single course ($3).
+fn study_scope() -> String {
+    format!(
+        "SELECT d.id FROM {decks} d
+         WHERE (d.user_id = $1
+             OR EXISTS (SELECT 1 FROM {deck_subs} s WHERE s.deck_id = d.id AND s.user_id = $1)
+             OR EXISTS (SELECT 1 FROM {course_decks} cd JOIN {courses} c ON c.id = cd.course_id
+                        WHERE cd.deck_id = d.id
+                          AND (c.user_id = $1
+                               OR EXISTS (SELECT 1 FROM {course_subs} cs WHERE cs.course_id = c.id AND cs.user_id = $1))))
+           AND ($2::int IS NULL OR d.id = $2)
+           AND ($3::int IS NULL OR EXISTS (SELECT 1 FROM {course_decks} cd WHERE cd.deck_id = d.id AND cd.course_id = $3))",
+        decks = Deck::table(),
+        courses = Course::table(),
+        deck_subs = DeckSubscription::table(),
+        course_subs = CourseSubscription::table(),
+        course_decks = CourseDeck::table(),
+    )
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct QueuedCard {
+    pub card: Card,
+    pub due: Option<DateTime<Utc>>,
+    pub new: bool,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StudyQueue {
+    pub cards: Vec<QueuedCard>,
+    pub new_remaining: i32,
+    pub reviews_remaining: i32,
+}
+
+/// Start of the current day, which is when daily limits reset
+fn start_of_day(now: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&now.date_naive().and_hms_opt(0, 0, 0).unwrap())
+}
+
+#[get("/queue?<deck>&<course>&<new_limit>&<review_limit>")]
+pub async fn queue(mut db: Connection<Db>, user: AuthenticatedUser, deck: Option<i32>, course: Option<i32>, new_limit: Option<i32>, review_limit: Option<i32>) -> Result<Json<StudyQueue>, Custom<String>> {
+    let now = Utc::now();
+    let error = |_| Custom(
+        Status::InternalServerError,
+        "Could not load study queue. Please try again.".to_string(),
+    );
+
+    let settings = sqlx::query(format!("SELECT * FROM {} WHERE user_id = $1", Settings::table()).as_str())
+        .bind(user.id())
+        .fetch_optional(&mut *db)
+        .await
+        .map_err(error)?
+        .map(Settings::from);
+
+    let new_per_day = new_limit
+        .or(settings.as_ref().and_then(|s| s.new_cards_per_day))
+        .unwrap_or(DEFAULT_NEW_CARDS_PER_DAY);
+    let reviews_per_day = review_limit
+        .or(settings.as_ref().and_then(|s| s.reviews_per_day))
+        .unwrap_or(DEFAULT_REVIEWS_PER_DAY);
+
+    // Cards answered for the first time today count against the new card limit
+    let studied_today = sqlx::query(format!(
+            "SELECT COUNT(DISTINCT card_id) FILTER (WHERE prev_due IS NULL),
+                    COUNT(DISTINCT card_id) FILTER (WHERE prev_due IS NOT NULL)
+             FROM {} WHERE user_id = $1 AND ts >= $2 AND NOT undone", Review::table()).as_str())
+        .bind(user.id())
+        .bind(start_of_day(now))
+        .fetch_one(&mut *db)
+        .await
+        .map_err(error)?;
+    let new_remaining = (new_per_day as i64 - studied_today.get::<i64, _>(0)).max(0) as i32;
+    let reviews_remaining = (reviews_per_day as i64 - studied_today.get::<i64, _>(1)).max(0) as i32;
+
+    // Most overdue cards first, relative to how long their interval is
+    let mut cards = sqlx::query(format!(
+            "SELECT c.*, h.due AS due FROM {} c JOIN {} h ON h.card_id = c.id AND h.user_id = $1
+             WHERE c.deck_id IN ({}) AND h.due <= $4
+             ORDER BY EXTRACT(EPOCH FROM ($4 - h.due)) / GREATEST(h.interval, 1) DESC, h.due
+             LIMIT $5", Card::table(), History::table(), study_scope()).as_str())
+        .bind(user.id())
+        .bind(deck)
+        .bind(course)
+        .bind(now)
+        .bind(reviews_remaining as i64)
+        .fetch(&mut *db)
+        .map_ok(|r| QueuedCard { due: Some(r.get("due")), card: Card::from(r), new: false })
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(error)?;
+
+    let new_cards = sqlx::query(format!(
+            "SELECT c.* FROM {} c
+             WHERE c.deck_id IN ({})
+               AND NOT EXISTS (SELECT 1 FROM {} h WHERE h.card_id = c.id AND h.user_id = $1)
+             ORDER BY c.id
+             LIMIT $4", Card::table(), study_scope(), History::table()).as_str())
+        .bind(user.id())
+        .bind(deck)
+        .bind(course)
+        .bind(new_remaining as i64)
+        .fetch(&mut *db)
+        .map_ok(|r| QueuedCard { due: None, card: Card::from(r), new: true })
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(error)?;
+
+    cards.extend(new_cards);
+
+    Ok(Json(StudyQueue {
+        cards,
+        new_remaining,
+        reviews_remaining,
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReviewRequest {
+    pub card_id: i32,
+    pub grade: Grade,
+    /// Time taken to answer, in milliseconds
+    pub response_time: i32,
+    pub session_id: Option<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReviewResult {
+    pub history: History,
+    pub due: DateTime<Utc>,
+}
+
+#[post("/review", data = "<review>")]
+pub async fn review(mut db: Connection<Db>, user: AuthenticatedUser, review: Json<ReviewRequest>) -> Result<Json<ReviewResult>, Custom<String>> {
+    let now = Utc::now();
+    let error = |_| Custom(
+        Status::InternalServerError,
+        "Could not record review. Please try again.".to_string(),
+    );
+
+    let mut tx = (&mut *db).begin().await.map_err(error)?;
+
+    let card = sqlx::query(format!(
+            "SELECT c.id FROM {} c WHERE c.id = $4 AND c.deck_id IN ({})",
+            Card::table(), study_scope()).as_str())
+        .bind(user.id())
+        .bind(None::<i32>)
+        .bind(None::<i32>)
+        .bind(review.card_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?;
+
+    if card.is_none() {
+        return Err(Custom(
+            Status::NotFound,
+            "Card does not exist or is not being studied.".to_string(),
+        ));
+    }
+
+    if let Some(session_id) = review.session_id {
+        let session = sqlx::query(format!("SELECT id FROM {} WHERE id = $1 AND user_id = $2 AND finished_at IS NULL", StudySession::table()).as_str())
+            .bind(session_id)
+            .bind(user.id())
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(error)?;
+
+        if session.is_none() {
+            return Err(Custom(
+                Status::Conflict,
+                "Study session does not exist or has already finished.".to_string(),
+            ));
+        }
+    }
+
+    let algorithm = sqlx::query(format!("SELECT * FROM {} WHERE user_id = $1", Settings::table()).as_str())
+        .bind(user.id())
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?
+        .map(|r| Settings::from(r).algorithm())
+        .unwrap_or_default();
+
+    // Lock the existing history row so concurrent submissions for the same card are applied one at a time
+    let history = sqlx::query(format!("SELECT * FROM {} WHERE user_id = $1 AND card_id = $2 FOR UPDATE", History::table()).as_str())
+        .bind(user.id())
+        .bind(review.card_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?;
+
+    let previous = history.map(History::from);
+    let response_time = review.response_time.max(0);
+    let log = Review::record(user.id(), review.card_id, review.grade, response_time, review.session_id, previous.as_ref(), now);
+
+    let mut history = previous.unwrap_or_else(|| History::unseen(user.id(), review.card_id, now));
+    history.time_spent += response_time as i64;
+    let state = history.review(review.grade, algorithm, now);
+
+    let history = history.save_in(&mut *tx).await.map_err(error)?;
+    log.save_in(&mut *tx).await.map_err(error)?;
+    tx.commit().await.map_err(error)?;
+
+    Ok(Json(ReviewResult {
+        history,
+        due: state.due,
+    }))
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UndoResult {
+    pub card_id: i32,
+    /// Restored history, or `None` if the undone review was the card's first
+    pub history: Option<History>,
+}
+
+#[post("/undo?<session>")]
+pub async fn undo(mut db: Connection<Db>, user: AuthenticatedUser, session: Option<i32>) -> Result<Json<UndoResult>, Custom<String>> {
+    let now = Utc::now();
+    let error = |_| Custom(
+        Status::InternalServerError,
+        "Could not undo review. Please try again.".to_string(),
+    );
+
+    let mut tx = (&mut *db).begin().await.map_err(error)?;
+
+    let review = sqlx::query(format!(
+            "SELECT * FROM {} WHERE user_id = $1 AND NOT undone
+               AND (session_id = $3 OR ($3::int IS NULL AND ts >= $2))
+             ORDER BY ts DESC, id DESC LIMIT 1 FOR UPDATE", Review::table()).as_str())
+        .bind(user.id())
+        .bind(now - Duration::minutes(UNDO_WINDOW))
+        .bind(session)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?;
+
+    if review.is_none() {
+        return Err(Custom(
+            Status::NotFound,
+            "There is no review to undo.".to_string(),
+        ));
+    }
+
+    let mut review = Review::from(review.unwrap());
+
+    // Undoing an older review would restore the card from before it and lose the later ones
+    let newer = sqlx::query(format!(
+            "SELECT id FROM {} WHERE user_id = $1 AND card_id = $2 AND NOT undone AND (ts, id) > ($3, $4) LIMIT 1",
+            Review::table()).as_str())
+        .bind(user.id())
+        .bind(review.card_id)
+        .bind(review.ts)
+        .bind(review.id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?;
+
+    if newer.is_some() {
+        return Err(Custom(
+            Status::Conflict,
+            "This card has been reviewed again since. Please undo that review first.".to_string(),
+        ));
+    }
+
+    let history = sqlx::query(format!("SELECT * FROM {} WHERE user_id = $1 AND card_id = $2 FOR UPDATE", History::table()).as_str())
+        .bind(user.id())
+        .bind(review.card_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?
+        .map(History::from);
+
+    let history = match history {
+        Some(mut history) => {
+            if review.restore(&mut history) {
+                Some(history.save_in(&mut *tx).await.map_err(error)?)
+            } else {
+                // The card was new before this review, so it goes back to being unseen
+                sqlx::query(format!("DELETE FROM {} WHERE id = $1", History::table()).as_str())
+                    .bind(history.id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(error)?;
+                None
+            }
+        }
+        None => None,
+    };
+
+    review.undone = true;
+    review.save_in(&mut *tx).await.map_err(error)?;
+    tx.commit().await.map_err(error)?;
+
+    Ok(Json(UndoResult {
+        card_id: review.card_id,
+        history,
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SessionRequest {
+    pub deck_id: Option<i32>,
+    pub course_id: Option<i32>,
+}
+
+#[post("/sessions", data = "<request>")]
+pub async fn start_session(mut db: Connection<Db>, user: AuthenticatedUser, request: Json<SessionRequest>) -> Result<Created<Json<StudySession>>, Custom<String>> {
+    let error = |_| Custom(
+        Status::InternalServerError,
+        "Could not start study session. Please try again.".to_string(),
+    );
+
+    if request.deck_id.is_some() || request.course_id.is_some() {
+        let decks = sqlx::query(format!("SELECT EXISTS ({})", study_scope()).as_str())
+            .bind(user.id())
+            .bind(request.deck_id)
+            .bind(request.course_id)
+            .fetch_one(&mut *db)
+            .await
+            .map_err(error)?;
+
+        if !decks.get::<bool, _>(0) {
+            return Err(Custom(
+                Status::NotFound,
+                "There are no decks to study here.".to_string(),
+            ));
+        }
+    }
+
+    let session = StudySession::new(user.id(), request.deck_id, request.course_id, Utc::now(), None, 0, 0, 0, 0);
+    let (session, _db) = session.save(db).await;
+
+    match session {
+        None => Err(Custom(
+            Status::InternalServerError,
+            "Could not start study session. Please try again.".to_string(),
+        )),
+        Some(s) => Ok(Created::new("/api/study/sessions").body(Json(s)))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SessionSummary {
+    pub session: StudySession,
+    /// Share of answers that were not "again", between 0 and 1
+    pub accuracy: f64,
+    pub new_cards: i64,
+    pub again: i64,
+    pub hard: i64,
+    pub good: i64,
+    pub easy: i64,
+}
+
+#[post("/sessions/<id>/finish")]
+pub async fn finish_session(mut db: Connection<Db>, user: AuthenticatedUser, id: i32) -> Result<Json<SessionSummary>, Custom<String>> {
+    let error = |_| Custom(
+        Status::InternalServerError,
+        "Could not finish study session. Please try again.".to_string(),
+    );
+
+    let mut tx = (&mut *db).begin().await.map_err(error)?;
+
+    let session = sqlx::query(format!("SELECT * FROM {} WHERE id = $1 AND user_id = $2 FOR UPDATE", StudySession::table()).as_str())
+        .bind(id)
+        .bind(user.id())
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?;
+
+    if session.is_none() {
+        return Err(Custom(
+            Status::NotFound,
+            "Study session does not exist.".to_string(),
+        ));
+    }
+
+    let mut session = StudySession::from(session.unwrap());
+
+    let stats = sqlx::query(format!(
+            "SELECT COUNT(DISTINCT card_id), COUNT(*), COALESCE(SUM(response_time), 0),
+                    COUNT(*) FILTER (WHERE prev_due IS NULL),
+                    COUNT(*) FILTER (WHERE grade = 1), COUNT(*) FILTER (WHERE grade = 2),
+                    COUNT(*) FILTER (WHERE grade = 3), COUNT(*) FILTER (WHERE grade = 4)
+             FROM {} WHERE session_id = $1 AND NOT undone", Review::table()).as_str())
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(error)?;
+
+    let again = stats.get::<i64, _>(4);
+    session.cards_seen = stats.get::<i64, _>(0) as i32;
+    session.num_reviews = stats.get::<i64, _>(1) as i32;
+    session.num_correct = session.num_reviews - again as i32;
+    session.time_spent = stats.get::<i64, _>(2);
+
+    // Finishing again only refreshes the numbers, the session keeps its original end time
+    if session.finished_at.is_none() {
+        session.finished_at = Some(Utc::now());
+    }
+
+    let session = session.save_in(&mut *tx).await.map_err(error)?;
+    tx.commit().await.map_err(error)?;
+
+    Ok(Json(SessionSummary {
+        accuracy: if session.num_reviews > 0 { session.num_correct as f64 / session.num_reviews as f64 } else { 0.0 },
+        session,
+        new_cards: stats.get::<i64, _>(3),
+        again,
+        hard: stats.get::<i64, _>(5),
+        good: stats.get::<i64, _>(6),
+        easy: stats.get::<i64, _>(7),
+    }))
+}
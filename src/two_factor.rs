@@ -0,0 +1,356 @@
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use chrono::{Duration, Utc};
+use rand_core::{OsRng, RngCore};
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::{Deserialize, Serialize, json::Json};
+use rocket::State;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Acquire, PgConnection};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use totp_rs::{Algorithm, Secret, TOTP};
+use super::Db;
+use super::auth::{AuthConfig, AuthenticatedUser};
+use super::models::*;
+use super::session::{self, ClientInfo};
+use super::user::{self, JwtToken};
+
+/// Shown as the account's provider in authenticator apps
+const ISSUER: &str = "Memra";
+/// Seconds each code is valid for
+const STEP: u64 = 30;
+/// Minutes between entering the password and the code
+const PENDING_LIFETIME: i64 = 5;
+/// Wrong codes after which the password has to be entered again
+const MAX_ATTEMPTS: u32 = 5;
+const RECOVERY_CODES: usize = 10;
+/// Characters of recovery codes, without ones that are easily confused
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const PURPOSE: &str = "two_factor";
+
+fn error(_: sqlx::Error) -> Custom<String> {
+    Custom(
+        Status::InternalServerError,
+        "Could not update two-factor authentication. Please try again.".to_string(),
+    )
+}
+
+fn invalid_code() -> Custom<String> {
+    Custom(
+        Status::BadRequest,
+        "Invalid code.".to_string(),
+    )
+}
+
+fn totp(secret: &str, account: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    Some(TOTP::new_unchecked(Algorithm::SHA1, 6, 1, STEP, secret, Some(ISSUER.to_string()), account.to_string()))
+}
+
+/// Time step a code belongs to, allowing for one step of clock drift
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let step = (now / STEP) as i64;
+    (step - 1..=step + 1).find(|s| totp.generate(*s as u64 * STEP) == code)
+}
+
+/// Recovery codes are compared without dashes or case
+fn normalize(code: &str) -> String {
+    code.trim().to_lowercase().replace('-', "")
+}
+
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 10];
+    OsRng.fill_bytes(&mut bytes);
+    let code: String = bytes.iter().map(|b| RECOVERY_ALPHABET[*b as usize % RECOVERY_ALPHABET.len()] as char).collect();
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+async fn find(conn: &mut PgConnection, user_id: i32) -> Result<Option<TwoFactor>, sqlx::Error> {
+    sqlx::query(format!("SELECT * FROM {} WHERE user_id = $1 FOR UPDATE", TwoFactor::table()).as_str())
+        .bind(user_id)
+        .fetch_optional(conn)
+        .await
+        .map(|r| r.map(TwoFactor::from))
+}
+
+pub(crate) async fn enabled(conn: &mut PgConnection, user_id: i32) -> Result<bool, sqlx::Error> {
+    sqlx::query(format!("SELECT id FROM {} WHERE user_id = $1 AND enabled", TwoFactor::table()).as_str())
+        .bind(user_id)
+        .fetch_optional(conn)
+        .await
+        .map(|r| r.is_some())
+}
+
+/// Checks an authenticator or recovery code, using it up. Should run in a transaction.
+async fn check_code(conn: &mut PgConnection, user_id: i32, code: &str) -> Result<bool, sqlx::Error> {
+    let factor = match find(&mut *conn, user_id).await? {
+        Some(factor) if factor.enabled => factor,
+        _ => return Ok(false),
+    };
+    let code = code.trim();
+
+    if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+        let step = totp(&factor.secret, "").and_then(|totp| matching_step(&totp, code));
+        return match step {
+            Some(step) if step > factor.last_step => {
+                sqlx::query(format!("UPDATE {} SET last_step = $1 WHERE id = $2", TwoFactor::table()).as_str())
+                    .bind(step)
+                    .bind(factor.id)
+                    .execute(conn)
+                    .await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        };
+    }
+
+    let codes = sqlx::query(format!("SELECT * FROM {} WHERE user_id = $1 AND used_at IS NULL", RecoveryCode::table()).as_str())
+        .bind(user_id)
+        .fetch_all(&mut *conn)
+        .await?;
+    let code = normalize(code);
+
+    for recovery in codes.into_iter().map(RecoveryCode::from) {
+        let matches = PasswordHash::new(&recovery.code_hash)
+            .is_ok_and(|hash| Argon2::default().verify_password(code.as_bytes(), &hash).is_ok());
+        if matches {
+            sqlx::query(format!("UPDATE {} SET used_at = $1 WHERE id = $2", RecoveryCode::table()).as_str())
+                .bind(Utc::now())
+                .bind(recovery.id)
+                .execute(conn)
+                .await?;
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingClaims {
+    sub: i32,
+    purpose: String,
+    jti: String,
+    exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aud: Option<String>,
+}
+
+/// Returned by `login` instead of tokens when the account needs a code
+#[derive(Serialize)]
+pub struct PendingLogin {
+    pub two_factor_token: String,
+    /// Seconds until `two_factor_token` expires
+    pub expires_in: i64,
+}
+
+pub(crate) fn pending_login(config: &AuthConfig, user: &User) -> Result<PendingLogin, Custom<String>> {
+    let mut jti = [0u8; 16];
+    OsRng.fill_bytes(&mut jti);
+
+    let claims = PendingClaims {
+        sub: user.id.unwrap(),
+        purpose: PURPOSE.to_string(),
+        jti: jti.iter().map(|b| format!("{:02x}", b)).collect(),
+        exp: (Utc::now() + Duration::minutes(PENDING_LIFETIME)).timestamp() as usize,
+        iss: config.issuer(),
+        aud: config.audience(),
+    };
+
+    Ok(PendingLogin {
+        two_factor_token: config.encode(&claims)?,
+        expires_in: PENDING_LIFETIME * 60,
+    })
+}
+
+/// Wrong codes entered per pending login
+#[derive(Default)]
+pub struct PendingAttempts {
+    failed: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl PendingAttempts {
+    fn exhausted(&self, jti: &str) -> bool {
+        self.failed.lock().unwrap().get(jti).is_some_and(|(count, _)| *count >= MAX_ATTEMPTS)
+    }
+
+    fn fail(&self, jti: &str) {
+        let mut failed = self.failed.lock().unwrap();
+        failed.retain(|_, (_, at)| at.elapsed().as_secs() < PENDING_LIFETIME as u64 * 60);
+        failed.entry(jti.to_string()).or_insert((0, Instant::now())).0 += 1;
+    }
+}
+
+#[derive(Serialize)]
+pub struct Enrollment {
+    /// Base32 secret, for entering it by hand
+    pub secret: String,
+    /// `otpauth://` URI, usually shown as a QR code
+    pub otpauth_uri: String,
+}
+
+#[post("/2fa/enroll")]
+pub async fn enroll(mut db: Connection<Db>, user: AuthenticatedUser) -> Result<Json<Enrollment>, Custom<String>> {
+    let mut tx = (&mut *db).begin().await.map_err(error)?;
+
+    if let Some(factor) = find(&mut *tx, user.id()).await.map_err(error)? {
+        if factor.enabled {
+            return Err(Custom(
+                Status::Conflict,
+                "Two-factor authentication is already enabled.".to_string(),
+            ));
+        }
+        // Enrolling again replaces a secret that was never confirmed
+        sqlx::query(format!("DELETE FROM {} WHERE id = $1", TwoFactor::table()).as_str())
+            .bind(factor.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(error)?;
+    }
+
+    let mut secret = [0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    let totp = TOTP::new_unchecked(Algorithm::SHA1, 6, 1, STEP, secret.to_vec(), Some(ISSUER.to_string()), user.username().to_string());
+
+    TwoFactor::new(user.id(), totp.get_secret_base32(), false, 0, Utc::now())
+        .save_in(&mut *tx).await.map_err(error)?;
+
+    tx.commit().await.map_err(error)?;
+
+    Ok(Json(Enrollment {
+        secret: totp.get_secret_base32(),
+        otpauth_uri: totp.get_url(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    /// Shown only once; each can replace an authenticator code one time
+    pub recovery_codes: Vec<String>,
+}
+
+#[post("/2fa/confirm", data = "<request>")]
+pub async fn confirm(mut db: Connection<Db>, user: AuthenticatedUser, request: Json<CodeRequest>) -> Result<Json<RecoveryCodes>, Custom<String>> {
+    let mut tx = (&mut *db).begin().await.map_err(error)?;
+
+    let factor = match find(&mut *tx, user.id()).await.map_err(error)? {
+        None => return Err(Custom(
+            Status::NotFound,
+            "Two-factor authentication has not been set up.".to_string(),
+        )),
+        Some(factor) if factor.enabled => return Err(Custom(
+            Status::Conflict,
+            "Two-factor authentication is already enabled.".to_string(),
+        )),
+        Some(factor) => factor,
+    };
+
+    let step = totp(&factor.secret, "")
+        .and_then(|totp| matching_step(&totp, request.code.trim()))
+        .ok_or_else(invalid_code)?;
+
+    sqlx::query(format!("UPDATE {} SET enabled = true, last_step = $1 WHERE id = $2", TwoFactor::table()).as_str())
+        .bind(step)
+        .bind(factor.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(error)?;
+
+    sqlx::query(format!("DELETE FROM {} WHERE user_id = $1", RecoveryCode::table()).as_str())
+        .bind(user.id())
+        .execute(&mut *tx)
+        .await
+        .map_err(error)?;
+
+    let mut recovery_codes = Vec::with_capacity(RECOVERY_CODES);
+    for _ in 0..RECOVERY_CODES {
+        let code = generate_recovery_code();
+        RecoveryCode::new(user.id(), user::hash(&normalize(&code))?, None)
+            .save_in(&mut *tx).await.map_err(error)?;
+        recovery_codes.push(code);
+    }
+
+    tx.commit().await.map_err(error)?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+#[delete("/2fa", data = "<request>")]
+pub async fn disable(mut db: Connection<Db>, user: AuthenticatedUser, request: Json<CodeRequest>) -> Result<Json<bool>, Custom<String>> {
+    let mut tx = (&mut *db).begin().await.map_err(error)?;
+
+    if !check_code(&mut *tx, user.id(), &request.code).await.map_err(error)? {
+        return Err(invalid_code());
+    }
+
+    for table in [TwoFactor::table(), RecoveryCode::table()] {
+        sqlx::query(format!("DELETE FROM {} WHERE user_id = $1", table).as_str())
+            .bind(user.id())
+            .execute(&mut *tx)
+            .await
+            .map_err(error)?;
+    }
+
+    tx.commit().await.map_err(error)?;
+
+    Ok(Json(true))
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorLogin {
+    two_factor_token: String,
+    code: String,
+}
+
+/// Second step of logging in, exchanging the token from `login` and a code for real tokens
+#[post("/login/2fa", data = "<request>")]
+pub async fn login(mut db: Connection<Db>, config: &State<AuthConfig>, attempts: &State<PendingAttempts>, client: ClientInfo, request: Json<TwoFactorLogin>) -> Result<Json<JwtToken>, Custom<String>> {
+    let expired = || Custom(
+        Status::Unauthorized,
+        "This login has expired. Please log in again.".to_string(),
+    );
+
+    let claims = config.decode::<PendingClaims>(&request.two_factor_token).map_err(|_| expired())?;
+    if claims.purpose != PURPOSE || attempts.exhausted(&claims.jti) {
+        return Err(expired());
+    }
+
+    let mut tx = (&mut *db).begin().await.map_err(error)?;
+
+    if !check_code(&mut *tx, claims.sub, &request.code).await.map_err(error)? {
+        attempts.fail(&claims.jti);
+        return Err(Custom(
+            Status::Unauthorized,
+            "Invalid code.".to_string(),
+        ));
+    }
+
+    let user = sqlx::query(format!("SELECT * FROM {} WHERE id = $1", User::table()).as_str())
+        .bind(claims.sub)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?
+        .map(User::from)
+        .ok_or_else(expired)?;
+
+    if user.disabled {
+        return Err(Custom(
+            Status::Forbidden,
+            "This account has been disabled.".to_string(),
+        ));
+    }
+
+    tx.commit().await.map_err(error)?;
+
+    Ok(Json(session::start(&mut *db, config, user, client).await?))
+}
@@ -7,15 +7,22 @@ use argon2::{
 };
 
 use rocket::http::Status;
+use rocket::State;
 use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx::{self, Acquire};
 use rocket::serde::{Deserialize, Serialize, json::Json};
 use super::{Db, Result};
 use super::auth::AuthenticatedUser;
 use rocket::response::status::{Created, Custom};
 use super::auth;
+use super::mail::Mailer;
+use super::session::{self, ClientInfo};
+use super::two_factor::{self, PendingLogin};
+use super::verification::{self, ResendThrottle};
 use super::models::*;
+use memra::requires_role;
 
-fn hash(text: &String) -> Result<String, Custom<String>> {
+pub(crate) fn hash(text: &String) -> Result<String, Custom<String>> {
     let salt = SaltString::generate(&mut OsRng);
     // Argon2 with default params (Argon2id v19)
     let argon2 = Argon2::default();
@@ -55,14 +62,65 @@ pub async fn read_user(db: rocket_db_pools::Connection<crate::Db>, user: crate::
 }
 
 #[delete("/")]
-pub async fn delete_user(db: Connection<Db>, user: AuthenticatedUser) -> Result<Option<()>> {
+pub async fn delete_user(mut db: Connection<Db>, config: &State<auth::AuthConfig>, user: AuthenticatedUser) -> Result<Option<()>> {
+    config.revoke_user(&mut *db, user.id()).await?;
     let (rows_affected, _db) = User::delete(user.id(), db).await;
     Ok((rows_affected? == 1).then(|| ()))
 }
 
+#[derive(Deserialize)]
+pub struct RoleChange {
+    role: String,
+}
+
+/// Gives another user a role. Their access tokens are revoked, so it applies from their next refresh.
+#[requires_role(admin)]
+#[put("/<id>/role", data = "<change>")]
+pub async fn set_role(mut db: Connection<Db>, config: &State<auth::AuthConfig>, user: AuthenticatedUser, id: i32, change: Json<RoleChange>) -> Result<Option<()>, Custom<String>> {
+    let role = auth::Role::parse(&change.role).ok_or_else(|| Custom(
+        Status::BadRequest,
+        format!("Unknown role {}.", change.role),
+    ))?;
+
+    if id == user.id() {
+        return Err(Custom(
+            Status::Forbidden,
+            "You cannot change your own role.".to_string(),
+        ));
+    }
+
+    let error = |_| Custom(
+        Status::InternalServerError,
+        "Could not change role. Please try again.".to_string(),
+    );
+    let updated = sqlx::query(format!("UPDATE {} SET role = $1 WHERE id = $2", User::table()).as_str())
+        .bind(role.as_str())
+        .bind(id)
+        .execute(&mut *db)
+        .await
+        .map_err(error)?
+        .rows_affected();
+
+    if updated == 0 {
+        return Ok(None);
+    }
+
+    config.revoke_user(&mut *db, id).await.map_err(error)?;
+    Ok(Some(()))
+}
+
 #[put("/change_password", data = "<password>")]
-pub async fn change_password(db: Connection<Db>, user: AuthenticatedUser, password: String) -> Result<Json<bool>, Custom<String>> {
-    let (creds, db) = user.data.find_credentials(db).await;
+pub async fn change_password(db: Connection<Db>, config: &State<auth::AuthConfig>, user: AuthenticatedUser, password: String) -> Result<Json<bool>, Custom<String>> {
+    let (account, db) = user.load(db).await;
+
+    if account.is_none() {
+        return Err(Custom(
+            Status::NotFound,
+            "This account no longer exists.".to_string(),
+        ));
+    }
+
+    let (creds, db) = account.unwrap().find_credentials(db).await;
 
     if creds.len() == 0 {
         return Err(Custom(
@@ -73,14 +131,38 @@ pub async fn change_password(db: Connection<Db>, user: AuthenticatedUser, passwo
 
     let mut creds = creds.into_iter().next().unwrap();
     creds.password = hash(&password)?;
-    let (result, _db) = creds.save(db).await;
+    let (result, mut db) = creds.save(db).await;
+
+    if result.is_none() {
+        return Ok(Json(false));
+    }
+
+    // Everyone holding an old token or refresh token has to log in with the new password
+    let error = |_| Custom(
+        Status::InternalServerError,
+        "Could not log out other devices. Please try again.".to_string(),
+    );
+    config.revoke_user(&mut *db, user.id()).await.map_err(error)?;
+    session::revoke_all(&mut *db, user.id()).await.map_err(error)?;
 
-    Ok(Json(result.is_some()))
+    Ok(Json(true))
 }
 
 #[derive(Serialize)]
 pub struct JwtToken {
-    token: String,
+    pub token: String,
+    /// Exchanged at `/refresh` for new tokens once the access token expired
+    pub refresh_token: String,
+    /// Seconds until `token` expires
+    pub expires_in: i64,
+}
+
+/// Tokens, or a pending login when the account has two-factor authentication
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(JwtToken),
+    TwoFactor(PendingLogin),
 }
 
 #[derive(Deserialize)]
@@ -90,7 +172,7 @@ pub struct LoginRequest {
 }
 
 #[post("/login", data = "<credentials>")]
-pub async fn login(db: Connection<Db>, credentials: Json<LoginRequest>) -> Result<Json<JwtToken>, Custom<String>> {
+pub async fn login(db: Connection<Db>, config: &State<auth::AuthConfig>, client: ClientInfo, credentials: Json<LoginRequest>) -> Result<Json<LoginResponse>, Custom<String>> {
     let (user, db) = User::find_where("username", &credentials.username, db).await;
 
     if user.is_none() {
@@ -102,7 +184,14 @@ pub async fn login(db: Connection<Db>, credentials: Json<LoginRequest>) -> Resul
 
     let user = user.unwrap();
 
-    let (creds, _db) = user.find_credentials(db).await;
+    if user.disabled {
+        return Err(Custom(
+            Status::Forbidden,
+            "This account has been disabled.".to_string(),
+        ));
+    }
+
+    let (creds, mut db) = user.find_credentials(db).await;
 
     if creds.len() == 0 {
         return Err(Custom(
@@ -137,9 +226,21 @@ pub async fn login(db: Connection<Db>, credentials: Json<LoginRequest>) -> Resul
         ));
     }
 
-    let claim = auth::AuthenticatedUser::from_user(user);
+    Ok(Json(finish_login(&mut *db, config, user, client).await?))
+}
+
+/// Issues tokens to a user who proved their identity, unless a second factor is needed first
+pub(crate) async fn finish_login(conn: &mut sqlx::PgConnection, config: &auth::AuthConfig, user: User, client: ClientInfo) -> Result<LoginResponse, Custom<String>> {
+    let two_factor = two_factor::enabled(&mut *conn, user.id.unwrap()).await.map_err(|_| Custom(
+        Status::InternalServerError,
+        "Could not verify login credentials.".to_string(),
+    ))?;
+
+    if two_factor {
+        return Ok(LoginResponse::TwoFactor(two_factor::pending_login(config, &user)?));
+    }
 
-    Ok(Json(JwtToken { token: claim.to_token()? }))
+    Ok(LoginResponse::Tokens(session::start(conn, config, user, client).await?))
 }
 
 #[derive(Deserialize)]
@@ -151,7 +252,7 @@ pub struct Registration {
 }
 
 #[post("/register", data = "<registration>")]
-pub async fn register(db: Connection<Db>, registration: Json<Registration>) -> Result<Created<Json<JwtToken>>, Custom<String>> {
+pub async fn register(db: Connection<Db>, config: &State<auth::AuthConfig>, mailer: &State<Mailer>, throttle: &State<ResendThrottle>, client: ClientInfo, registration: Json<Registration>) -> Result<Created<Json<JwtToken>>, Custom<String>> {
     let (user, db) = User::new(
         registration.username.to_string(),
         registration.email.to_string(),
@@ -163,6 +264,8 @@ pub async fn register(db: Connection<Db>, registration: Json<Registration>) -> R
         Some(false),
         chrono::Utc::now(),
         chrono::Utc::now(),
+        false,
+        auth::Role::User.as_str().to_string(),
     ).save(db).await;
 
     if user.is_none() {
@@ -176,7 +279,7 @@ pub async fn register(db: Connection<Db>, registration: Json<Registration>) -> R
     
     let password_hash = hash(&registration.password)?;
 
-    let (creds, _db) = Credentials::new_from(&user, password_hash)
+    let (creds, mut db) = Credentials::new_from(&user, password_hash)
         .unwrap().save(db).await;
 
     if creds.is_none() {
@@ -186,7 +289,134 @@ pub async fn register(db: Connection<Db>, registration: Json<Registration>) -> R
         ));
     }
 
-    let claim = auth::AuthenticatedUser::from_user(user);
+    // Registration still succeeds if the email can't be sent, as it can be requested again
+    throttle.try_acquire(user.id.unwrap());
+    if let Err(e) = verification::send(config, mailer, &user).await {
+        warn!("Could not send verification email: {}", e);
+    }
+
+    let token = session::start(&mut *db, config, user, client).await?;
+
+    Ok(Created::new("/user").body(Json(token)))
+}
+
+/// Minutes a password reset link stays valid
+const RESET_LIFETIME: i64 = 60;
+/// Minutes before another reset link is sent to the same account
+const RESET_INTERVAL: i64 = 2;
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+/// Emails a reset link if the address belongs to an account. The response is the same either way.
+#[post("/forgot_password", data = "<request>")]
+pub async fn forgot_password(mut db: Connection<Db>, mailer: &State<Mailer>, request: Json<ForgotPassword>) -> Result<Status, Custom<String>> {
+    let error = |_| Custom(
+        Status::InternalServerError,
+        "Could not reset password. Please try again.".to_string(),
+    );
+    let now = chrono::Utc::now();
+
+    let user = sqlx::query(format!("SELECT * FROM {} WHERE email = $1 AND NOT disabled", User::table()).as_str())
+        .bind(&request.email)
+        .fetch_optional(&mut *db)
+        .await
+        .map_err(error)?
+        .map(User::from);
+
+    let user = match user {
+        Some(user) => user,
+        None => return Ok(Status::Accepted),
+    };
+
+    let recent = sqlx::query(format!("SELECT id FROM {} WHERE user_id = $1 AND created_at > $2", PasswordReset::table()).as_str())
+        .bind(user.id)
+        .bind(now - chrono::Duration::minutes(RESET_INTERVAL))
+        .fetch_optional(&mut *db)
+        .await
+        .map_err(error)?;
+
+    if recent.is_some() {
+        return Ok(Status::Accepted);
+    }
+
+    let token = session::generate_secret();
+    PasswordReset::new_from(&user, session::digest(&token), now, now + chrono::Duration::minutes(RESET_LIFETIME), None)
+        .unwrap().save_in(&mut *db).await.map_err(error)?;
+
+    let body = format!(
+        "Hi {},\n\nsomeone asked to reset the password of your Memra account. Open this link to choose a new one:\n\n{}\n\n\
+         The link is valid for {} minutes. If you didn't ask for it, you can ignore this message and keep your password.\n",
+        user.real_name.as_deref().unwrap_or(&user.username),
+        mailer.url(&format!("/reset_password?token={}", token)),
+        RESET_LIFETIME,
+    );
+
+    // Sent in the background, so the response takes as long as for unknown addresses
+    let mailer = mailer.inner().clone();
+    rocket::tokio::spawn(async move {
+        if let Err(e) = mailer.send(user.real_name.as_deref(), &user.email, "Reset your password", body).await {
+            error!("Could not send password reset email: {}", e);
+        }
+    });
+
+    Ok(Status::Accepted)
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
 
-    Ok(Created::new("/user").body(Json(JwtToken { token: claim.to_token()? })))
+#[post("/reset_password", data = "<request>")]
+pub async fn reset_password(mut db: Connection<Db>, config: &State<auth::AuthConfig>, request: Json<ResetPassword>) -> Result<Json<bool>, Custom<String>> {
+    let error = |_| Custom(
+        Status::InternalServerError,
+        "Could not reset password. Please try again.".to_string(),
+    );
+    let now = chrono::Utc::now();
+    let password_hash = hash(&request.password)?;
+
+    let mut tx = (&mut *db).begin().await.map_err(error)?;
+
+    let reset = sqlx::query(format!("SELECT * FROM {} WHERE token_hash = $1 FOR UPDATE", PasswordReset::table()).as_str())
+        .bind(session::digest(&request.token))
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error)?
+        .map(PasswordReset::from)
+        .filter(|r| r.used_at.is_none() && r.expires_at > now);
+
+    let reset = match reset {
+        Some(reset) => reset,
+        None => return Err(Custom(
+            Status::BadRequest,
+            "This reset link is invalid, has expired or was already used.".to_string(),
+        )),
+    };
+
+    // Using one link invalidates all others sent to the account
+    sqlx::query(format!("UPDATE {} SET used_at = $1 WHERE user_id = $2 AND used_at IS NULL", PasswordReset::table()).as_str())
+        .bind(now)
+        .bind(reset.user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(error)?;
+
+    sqlx::query(format!("UPDATE {} SET password = $1 WHERE user_id = $2", Credentials::table()).as_str())
+        .bind(password_hash)
+        .bind(reset.user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(error)?;
+
+    config.revoke_user(&mut *tx, reset.user_id).await.map_err(error)?;
+    session::revoke_all(&mut *tx, reset.user_id).await.map_err(error)?;
+
+    tx.commit().await.map_err(error)?;
+
+    Ok(Json(true))
 }
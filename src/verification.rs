@@ -0,0 +1,145 @@
+use chrono::{Duration, Utc};
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::State;
+use rocket_db_pools::Connection;
+use rocket_db_pools::sqlx;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use super::Db;
+use super::auth::{AuthConfig, AuthenticatedUser};
+use super::mail::Mailer;
+use super::models::*;
+
+/// Hours a verification link stays valid
+const VERIFICATION_LIFETIME: i64 = 48;
+/// Seconds before another verification email can be requested
+const RESEND_INTERVAL: u64 = 120;
+/// Keeps verification links from being accepted as any other kind of signed token
+const PURPOSE: &str = "verify_email";
+
+#[derive(Serialize, Deserialize)]
+struct VerificationClaims {
+    sub: i32,
+    /// Address being verified, so links stop working once it changes
+    email: String,
+    purpose: String,
+    exp: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aud: Option<String>,
+}
+
+/// When each user was last sent a verification email
+#[derive(Default)]
+pub struct ResendThrottle {
+    sent: Mutex<HashMap<i32, Instant>>,
+}
+
+impl ResendThrottle {
+    /// Records a new email unless the last one was sent too recently
+    pub fn try_acquire(&self, user_id: i32) -> bool {
+        let mut sent = self.sent.lock().unwrap();
+        if sent.get(&user_id).is_some_and(|at| at.elapsed().as_secs() < RESEND_INTERVAL) {
+            return false;
+        }
+        sent.retain(|_, at| at.elapsed().as_secs() < RESEND_INTERVAL);
+        sent.insert(user_id, Instant::now());
+        true
+    }
+}
+
+/// Emails the user a link that verifies their address
+pub(crate) async fn send(config: &AuthConfig, mailer: &Mailer, user: &User) -> Result<(), String> {
+    let claims = VerificationClaims {
+        sub: user.id.unwrap(),
+        email: user.email.clone(),
+        purpose: PURPOSE.to_string(),
+        exp: (Utc::now() + Duration::hours(VERIFICATION_LIFETIME)).timestamp() as usize,
+        iss: config.issuer(),
+        aud: config.audience(),
+    };
+    let token = config.encode(&claims).map_err(|e| e.1)?;
+
+    let body = format!(
+        "Hi {},\n\nplease confirm your email address for Memra by opening this link:\n\n{}\n\n\
+         The link is valid for {} hours. If you didn't sign up for Memra, you can ignore this message.\n",
+        user.real_name.as_deref().unwrap_or(&user.username),
+        mailer.url(&format!("/api/users/verify/{}", token)),
+        VERIFICATION_LIFETIME,
+    );
+
+    mailer.send(user.real_name.as_deref(), &user.email, "Confirm your email address", body).await
+}
+
+#[get("/verify/<token>")]
+pub async fn verify(mut db: Connection<Db>, config: &State<AuthConfig>, token: &str) -> Result<&'static str, Custom<String>> {
+    let invalid = || Custom(
+        Status::BadRequest,
+        "This verification link is invalid, has expired or was already used.".to_string(),
+    );
+
+    let claims = config.decode::<VerificationClaims>(token).map_err(|_| invalid())?;
+    if claims.purpose != PURPOSE {
+        return Err(invalid());
+    }
+
+    // Links are single-use, as they only apply to accounts that are not verified yet
+    let updated = sqlx::query(format!("UPDATE {} SET verified = true WHERE id = $1 AND email = $2 AND verified IS NOT TRUE", User::table()).as_str())
+        .bind(claims.sub)
+        .bind(&claims.email)
+        .execute(&mut *db)
+        .await
+        .map_err(|_| Custom(
+            Status::InternalServerError,
+            "Could not verify email address. Please try again.".to_string(),
+        ))?
+        .rows_affected();
+
+    match updated {
+        0 => Err(invalid()),
+        _ => Ok("Your email address has been verified."),
+    }
+}
+
+#[post("/verify/resend")]
+pub async fn resend(db: Connection<Db>, config: &State<AuthConfig>, mailer: &State<Mailer>, throttle: &State<ResendThrottle>, user: AuthenticatedUser) -> Result<Json<bool>, Custom<String>> {
+    let (account, _db) = user.load(db).await;
+
+    if account.is_none() {
+        return Err(Custom(
+            Status::NotFound,
+            "This account no longer exists.".to_string(),
+        ));
+    }
+
+    let account = account.unwrap();
+
+    if account.verified == Some(true) {
+        return Err(Custom(
+            Status::Conflict,
+            "This email address is already verified.".to_string(),
+        ));
+    }
+
+    if !throttle.try_acquire(user.id()) {
+        return Err(Custom(
+            Status::TooManyRequests,
+            "A verification email was sent recently. Please try again in a few minutes.".to_string(),
+        ));
+    }
+
+    send(config, mailer, &account).await.map_err(|e| {
+        error!("Could not send verification email: {}", e);
+        Custom(
+            Status::InternalServerError,
+            "Could not send verification email. Please try again.".to_string(),
+        )
+    })?;
+
+    Ok(Json(true))
+}